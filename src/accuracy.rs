@@ -0,0 +1,144 @@
+// Force accuracy report.
+//
+// Works out exact reference accelerations with the direct sum, then builds
+// the tree for a range of opening angles and compares. For each theta the
+// median and 99th percentile of the relative error per particle are reported
+// along with how long the tree took, so theta can be picked from numbers
+// rather than guessed. Run with --accuracy to print it for the demo scenarios.
+use std::{fmt, time::Duration, time::Instant};
+
+use crate::float::Real;
+use crate::softening::Softening;
+use crate::solver::{relative_errors, BarnesHut, DirectSum, ForceSolver};
+use crate::space::Space;
+use crate::tree::{Boundary, Particle};
+
+// each solver is timed this many times and the fastest run is kept so a
+// single slow run doesn't skew the report
+static TIMING_RUNS: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct ThetaAccuracy {
+    pub theta: Real,
+    pub median_error: Real,
+    pub p99_error: Real,
+    // building the tree and working out the gravity of every particle
+    pub tree_time: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccuracyReport {
+    pub particles: usize,
+    pub direct_time: Duration,
+    pub rows: Vec<ThetaAccuracy>,
+}
+
+// compare the tree against the direct sum for every theta
+pub fn accuracy_report<V: Space>(
+    list_of_points: &[Particle<V>],
+    thetas: &[Real],
+    softening: &Softening,
+) -> AccuracyReport {
+    let mut direct = DirectSum::<V>::new(*softening);
+    let (reference, direct_time) = timed_gravity(&mut direct, list_of_points);
+
+    let mut rows = vec![];
+    for theta in thetas {
+        let mut barnes_hut = BarnesHut::new(*theta, Boundary::Fit, true);
+        barnes_hut.set_softening(*softening);
+        let (approximate, tree_time) = timed_gravity(&mut barnes_hut, list_of_points);
+        let mut errors = relative_errors(&reference, &approximate);
+        // a particle with no pull on it has no relative error to speak of
+        errors.retain(|error| error.is_finite());
+        errors.sort_by(Real::total_cmp);
+        rows.push(ThetaAccuracy {
+            theta: *theta,
+            median_error: percentile(&errors, &0.5),
+            p99_error: percentile(&errors, &0.99),
+            tree_time,
+        });
+    }
+    AccuracyReport {
+        particles: list_of_points.len(),
+        direct_time,
+        rows,
+    }
+}
+
+// gravity of every particle from a fresh copy of the list and the fastest
+// time it took
+fn timed_gravity<V: Space>(
+    solver: &mut dyn ForceSolver<V>,
+    list_of_points: &[Particle<V>],
+) -> (Vec<Particle<V>>, Duration) {
+    let mut fastest = Duration::MAX;
+    let mut result = vec![];
+    for _ in 0..TIMING_RUNS {
+        result = list_of_points.to_vec();
+        for point in &mut result {
+            point.g_vector = V::zero();
+        }
+        let start = Instant::now();
+        solver.calc_gravity(&mut result);
+        fastest = fastest.min(start.elapsed());
+    }
+    (result, fastest)
+}
+
+// value at fraction of the way through sorted using the nearest rank
+pub fn percentile(sorted: &[Real], fraction: &Real) -> Real {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = Real::ceil(fraction * sorted.len() as Real) as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl fmt::Display for AccuracyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} particles, direct sum took {:?}",
+            self.particles, self.direct_time
+        )?;
+        writeln!(f, "theta  median error  99% error  tree time")?;
+        for row in &self.rows {
+            writeln!(
+                f,
+                "{:<5.2}  {:<12.3e}  {:<9.3e}  {:?}",
+                row.theta, row.median_error, row.p99_error, row.tree_time
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn percentile_uses_nearest_rank() {
+        let sorted: Vec<Real> = (1..=100).map(|i| i as Real).collect();
+        assert_eq!(percentile(&sorted, &0.5), 50.0);
+        assert_eq!(percentile(&sorted, &0.99), 99.0);
+        assert_eq!(percentile(&sorted, &1.0), 100.0);
+        assert_eq!(percentile(&sorted, &0.0), 1.0);
+        assert_eq!(percentile(&[], &0.5), 0.0);
+    }
+
+    #[test]
+    fn error_grows_with_theta() {
+        let list_of_points = fixtures::spiral(200, |i| 5.0 + Real::sqrt(i as Real) * 30.0, |_| 1.0);
+        let report = accuracy_report(&list_of_points, &[0.0, 0.3, 1.0], &Softening::None);
+        assert_eq!(report.particles, 200);
+        assert_eq!(report.rows.len(), 3);
+        // theta of zero opens everything so it is the direct sum
+        assert!(report.rows[0].p99_error < 1e-4);
+        assert!(report.rows[1].median_error < report.rows[2].median_error);
+        assert!(report.rows[1].median_error <= report.rows[1].p99_error);
+        // and the table has a line per theta
+        assert_eq!(report.to_string().lines().count(), 5);
+    }
+}
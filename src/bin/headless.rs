@@ -0,0 +1,260 @@
+// Runs a scenario with no window and writes snapshots and diagnostics to
+// disk, for batch runs on machines without a display. Build it without
+// macroquad with
+//
+//     cargo build --release --no-default-features --bin headless
+//
+// Every `--every` steps a snapshot_<step>.csv is written to the output
+// directory and a line is added to diagnostics.csv, see the output module.
+// The last step is always written.
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use rusty_orbit::float::Real;
+use rusty_orbit::units::UnitSystem;
+use rusty_orbit::{collision, integrator, output, scenario, Simulation, Solver};
+
+static USAGE: &str = "usage: headless <scenario> (--steps N | --until T) [options]
+
+scenarios: two-masses, debris-disk, inner-planets
+  --steps N            take N steps
+  --until T            run until time T, in the units of the scenario
+  --every K            write a snapshot every K steps (default 100)
+  --out DIR            directory to write to (default output)
+  --solver NAME        barnes-hut, direct or fmm (default barnes-hut)
+  --integrator NAME    semi-implicit, leapfrog, velocity, runge or yoshida
+  --block-steps        give every particle its own power of two step
+  --merge              merge particles that touch instead of bouncing";
+
+// how long to run for
+enum Length {
+    Steps(usize),
+    // in simulation time units
+    Until(Real),
+}
+
+struct Options {
+    name: &'static str,
+    units: UnitSystem,
+    simulation: Simulation,
+    length: Length,
+    every: usize,
+    out: PathBuf,
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("{} can't use {}", flag, value))
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let scenario_name = args.next().ok_or("no scenario given")?;
+    let (name, units, scenario) =
+        scenario::find(&scenario_name).ok_or(format!("no scenario called {}", scenario_name))?;
+    let mut simulation = Simulation::new(scenario::load_scenario(
+        &units,
+        scenario,
+        &scenario::DENSITY,
+    ));
+    let mut length = None;
+    let mut every = 100;
+    let mut out = PathBuf::from("output");
+
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--steps" => length = Some(Length::Steps(parse_value(&flag, args.next())?)),
+            "--until" => {
+                let end: Real = parse_value(&flag, args.next())?;
+                if !end.is_finite() || end <= 0.0 {
+                    return Err(format!("--until must be a time after 0, not {}", end));
+                }
+                length = Some(Length::Until(units.to_simulation_time(&end)));
+            }
+            "--every" => every = parse_value(&flag, args.next())?,
+            "--out" => out = PathBuf::from(parse_value::<String>(&flag, args.next())?),
+            "--solver" => {
+                simulation.solver = match parse_value::<String>(&flag, args.next())?.as_str() {
+                    "barnes-hut" => Solver::BarnesHut,
+                    "direct" => Solver::DirectSum,
+                    "fmm" => Solver::Fmm,
+                    other => return Err(format!("no solver called {}", other)),
+                }
+            }
+            "--integrator" => {
+                let wanted = parse_value::<String>(&flag, args.next())?;
+                simulation.integrator = integrator::all()
+                    .into_iter()
+                    .find(|integrator| integrator.name().starts_with(&wanted))
+                    .ok_or(format!("no integrator called {}", wanted))?;
+            }
+            "--block-steps" => simulation.block_steps = true,
+            "--merge" => simulation.response = collision::Response::Merge(scenario::DENSITY),
+            other => return Err(format!("unknown option {}", other)),
+        }
+    }
+    if every == 0 {
+        return Err("--every must be at least 1".to_string());
+    }
+    Ok(Options {
+        name,
+        units,
+        simulation,
+        length: length.ok_or("give either --steps or --until")?,
+        every,
+        out,
+    })
+}
+
+fn finished(options: &Options, step: usize) -> bool {
+    match options.length {
+        Length::Steps(steps) => step >= steps,
+        Length::Until(end) => options.simulation.time >= end,
+    }
+}
+
+fn write_snapshot(options: &Options, step: usize) -> std::io::Result<()> {
+    let path = options.out.join(format!("snapshot_{:06}.csv", step));
+    let mut writer = BufWriter::new(File::create(path)?);
+    output::write_snapshot(&mut writer, &options.simulation.particles, &options.units)?;
+    writer.flush()
+}
+
+fn run(mut options: Options) -> std::io::Result<()> {
+    fs::create_dir_all(&options.out)?;
+    let mut diagnostics = BufWriter::new(File::create(options.out.join("diagnostics.csv"))?);
+    writeln!(diagnostics, "{}", output::DIAGNOSTICS_HEADER)?;
+    let start_energy = options.simulation.total_energy();
+    output::write_diagnostics(
+        &mut diagnostics,
+        0,
+        &options.simulation,
+        &options.units,
+        &start_energy,
+    )?;
+    write_snapshot(&options, 0)?;
+
+    let mut step = 0;
+    while !finished(&options, step) {
+        match options.length {
+            Length::Steps(_) => options.simulation.step(),
+            Length::Until(end) => options.simulation.step_toward(&end),
+        };
+        step += 1;
+
+        if step % options.every == 0 || finished(&options, step) {
+            output::write_diagnostics(
+                &mut diagnostics,
+                step,
+                &options.simulation,
+                &options.units,
+                &start_energy,
+            )?;
+            write_snapshot(&options, step)?;
+        }
+    }
+    diagnostics.flush()?;
+    println!(
+        "{}: {} steps to time {} ({}) written to {}",
+        options.name,
+        step,
+        options.units.from_simulation_time(&options.simulation.time),
+        options.units.name(),
+        options.out.display()
+    );
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let options = match parse_options(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("failed to write the output: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        parse_options(args.split_whitespace().map(String::from))
+    }
+
+    fn error(args: &str) -> String {
+        parse(args).err().unwrap()
+    }
+
+    #[test]
+    fn parses_every_flag() {
+        let options = parse(
+            "Inner-Planets --until 2 --every 5 --out runs --solver fmm --integrator leap \
+             --block-steps --merge",
+        )
+        .unwrap();
+        assert_eq!(options.name, "inner planets");
+        assert_eq!(options.units, UnitSystem::Astronomical);
+        assert!(
+            matches!(options.length, Length::Until(end) if end == options.units.to_simulation_time(&2.0))
+        );
+        assert_eq!(options.every, 5);
+        assert_eq!(options.out, PathBuf::from("runs"));
+        assert_eq!(options.simulation.solver, Solver::Fmm);
+        assert_eq!(options.simulation.integrator.name(), "leapfrog (kdk)");
+        assert!(options.simulation.block_steps);
+        assert!(matches!(
+            options.simulation.response,
+            collision::Response::Merge(_)
+        ));
+
+        let options = parse("two-masses --steps 10").unwrap();
+        assert!(matches!(options.length, Length::Steps(10)));
+        assert_eq!(options.every, 100);
+        assert_eq!(options.out, PathBuf::from("output"));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(error(""), "no scenario given");
+        assert_eq!(error("pluto --steps 1"), "no scenario called pluto");
+        assert_eq!(error("two-masses"), "give either --steps or --until");
+        assert_eq!(error("two-masses --steps"), "--steps needs a value");
+        assert_eq!(error("two-masses --steps ten"), "--steps can't use ten");
+        assert_eq!(
+            error("two-masses --steps 1 --fast"),
+            "unknown option --fast"
+        );
+        assert_eq!(
+            error("two-masses --steps 1 --every 0"),
+            "--every must be at least 1"
+        );
+        assert_eq!(
+            error("two-masses --steps 1 --solver fast"),
+            "no solver called fast"
+        );
+    }
+
+    #[test]
+    fn until_must_be_after_the_start() {
+        for end in ["nan", "inf", "0", "-1", "-0.5"] {
+            let message = error(&format!("two-masses --until {}", end));
+            assert!(
+                message.starts_with("--until must be a time after 0"),
+                "{}",
+                message
+            );
+        }
+    }
+}
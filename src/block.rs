@@ -0,0 +1,259 @@
+// Hierarchical block time steps.
+//
+// Instead of one step for every particle, each particle gets its own step of
+// max_step / 2^level. Particles in close encounters sit on high levels and
+// are updated often while the rest are only kicked every so often. Because
+// every step is a power of two fraction of max_step the particles line up
+// again at the end of each block, which is max_step long.
+//
+// Inside a block the particles whose step ends at the current time are the
+// active set. Every other particle is predicted forward to that time from its
+// last position, velocity and acceleration so the tree is built from where
+// everyone should be, then only the active particles get a new acceleration.
+// The active particles are then corrected like velocity Verlet using the
+// average of the old and new acceleration.
+use crate::float::Real;
+use crate::space::Space;
+use crate::tree::{Particle, Vector};
+
+// fills in g_vector for the particles at the given indices. The whole list is
+// passed so the tree can be built from every particle. g_vector of the active
+// particles is cleared before the call
+pub type ActiveForces<'a, V = Vector> = dyn FnMut(&mut [Particle<V>], &[usize]) + 'a;
+
+#[derive(Debug, Clone)]
+pub struct BlockSteps<V = Vector> {
+    // length of a block and the largest step any particle takes
+    pub max_step: Real,
+    // the smallest step is max_step / 2^max_level
+    pub max_level: u32,
+    // accuracy factor. Each particle wants a step of eta * sqrt(softening / |a|)
+    pub eta: Real,
+    pub softening: Real,
+    // level of each particle
+    levels: Vec<u32>,
+    // tick inside the current block each particle was last updated at. A tick
+    // is the smallest step
+    last_tick: Vec<u64>,
+    // particles predicted forward to the current tick
+    predicted: Vec<Particle<V>>,
+    // number of accelerations worked out during the last block
+    evaluations: usize,
+}
+
+impl<V: Space> BlockSteps<V> {
+    pub fn new(max_step: Real, max_level: u32, eta: Real, softening: Real) -> BlockSteps<V> {
+        BlockSteps {
+            max_step,
+            max_level,
+            eta,
+            softening,
+            levels: vec![],
+            last_tick: vec![],
+            predicted: vec![],
+            evaluations: 0,
+        }
+    }
+
+    // level of every particle, in the same order as the particle list
+    pub fn levels(&self) -> &[u32] {
+        &self.levels
+    }
+
+    // how many particle accelerations were worked out during the last block
+    pub fn evaluations(&self) -> usize {
+        self.evaluations
+    }
+
+    // forget the levels so they are worked out again on the next step. Call
+    // this whenever particles are added, removed or moved by hand
+    pub fn reset(&mut self) {
+        self.levels.clear();
+    }
+
+    // number of ticks in a step at level
+    fn ticks(&self, level: u32) -> u64 {
+        1 << (self.max_level - level)
+    }
+
+    // the level whose step best fits the acceleration held in g_vector
+    fn level_for(&self, point: &Particle<V>) -> u32 {
+        let acceleration = point.g_vector.length();
+        if acceleration == 0.0 {
+            return 0;
+        }
+        let wanted = self.eta * Real::sqrt(self.softening / acceleration);
+        let mut level = 0;
+        let mut step = self.max_step;
+        while step > wanted && level < self.max_level {
+            step /= 2.0;
+            level += 1;
+        }
+        level
+    }
+
+    // advance every particle by max_step. All particles are in sync at the
+    // start and the end of the call
+    pub fn step(&mut self, list_of_points: &mut [Particle<V>], forces: &mut ActiveForces<V>) {
+        let count = list_of_points.len();
+        self.evaluations = 0;
+        if self.levels.len() != count {
+            // first step or the particles changed so everyone needs an acceleration
+            let all: Vec<usize> = (0..count).collect();
+            for point in list_of_points.iter_mut() {
+                point.g_vector = V::zero();
+            }
+            forces(list_of_points, &all);
+            self.evaluations += count;
+            self.levels = list_of_points
+                .iter()
+                .map(|point| self.level_for(point))
+                .collect();
+        }
+        self.last_tick.clear();
+        self.last_tick.resize(count, 0);
+        self.predicted.clear();
+        self.predicted.extend_from_slice(list_of_points);
+
+        let end = 1u64 << self.max_level;
+        let tick_time = self.max_step / end as Real;
+        let mut active = vec![];
+        let mut tick = 0;
+        while tick < end {
+            // jump to the next time any particle finishes its step
+            tick = (0..count)
+                .map(|i| self.last_tick[i] + self.ticks(self.levels[i]))
+                .min()
+                .unwrap_or(end);
+            active.clear();
+            active.extend(
+                (0..count).filter(|i| self.last_tick[*i] + self.ticks(self.levels[*i]) == tick),
+            );
+
+            // predict every particle to this tick
+            for (i, point) in list_of_points.iter().enumerate() {
+                let dt = (tick - self.last_tick[i]) as Real * tick_time;
+                let predicted = &mut self.predicted[i];
+                predicted.position =
+                    point.position + point.velocity * dt + point.g_vector * (0.5 * dt * dt);
+                predicted.velocity = point.velocity + point.g_vector * dt;
+                predicted.mass = point.mass;
+            }
+            for i in &active {
+                self.predicted[*i].g_vector = V::zero();
+            }
+            forces(&mut self.predicted, &active);
+            self.evaluations += active.len();
+
+            // correct the active particles with the new acceleration
+            for i in &active {
+                let i = *i;
+                let dt = (tick - self.last_tick[i]) as Real * tick_time;
+                let new_acceleration = self.predicted[i].g_vector;
+                let point = &mut list_of_points[i];
+                point.position = self.predicted[i].position;
+                point.velocity += (point.g_vector + new_acceleration) * (0.5 * dt);
+                point.g_vector = new_acceleration;
+                self.last_tick[i] = tick;
+
+                // Smaller steps are always allowed since this tick is on their grid.
+                // Larger steps are only taken one level at a time and only when
+                // this tick lines up with the larger step
+                let wanted = self.level_for(point);
+                let level = self.levels[i];
+                if wanted > level {
+                    self.levels[i] = wanted;
+                } else if wanted < level && tick % self.ticks(level - 1) == 0 {
+                    self.levels[i] = level - 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::total_energy;
+    use crate::softening::Softening;
+    use crate::solver::{DirectSum, ForceSolver};
+
+    // exact pairwise gravity on the active particles
+    fn direct(list_of_points: &mut [Particle], active: &[usize]) {
+        DirectSum::new(Softening::None).calc_gravity_active(list_of_points, active);
+    }
+
+    // a heavy particle with one light particle on a tight fast orbit and one
+    // on a wide slow orbit
+    fn two_orbits() -> Vec<Particle> {
+        let heavy = Particle {
+            position: Vector { x: 0.0, y: 0.0 },
+            velocity: Vector { x: 0.0, y: 0.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 1_000_000.0,
+        };
+        // circular speed is sqrt(G * M / r)
+        let close = Particle {
+            position: Vector { x: 60.0, y: 0.0 },
+            velocity: Vector {
+                x: 0.0,
+                y: Real::sqrt(1_000_000.0 / 60.0),
+            },
+            ..heavy
+        };
+        let far = Particle {
+            position: Vector { x: -800.0, y: 0.0 },
+            velocity: Vector {
+                x: 0.0,
+                y: -Real::sqrt(1_000_000.0 / 800.0),
+            },
+            ..heavy
+        };
+        vec![
+            heavy,
+            Particle { mass: 1.0, ..close },
+            Particle { mass: 1.0, ..far },
+        ]
+    }
+
+    #[test]
+    fn block_steps_put_close_encounters_on_small_steps() {
+        let mut list_of_points = two_orbits();
+        let mut block = BlockSteps::new(0.05, 8, 0.05, 1.0);
+        block.step(&mut list_of_points, &mut direct);
+
+        let levels = block.levels();
+        // the tight orbit needs a much smaller step than the wide one
+        assert!(levels[1] > levels[2] + 2, "{:?}", levels);
+        // and the quiet particles are updated far less often than the finest
+        // step would need
+        let finest = list_of_points.len() << block.max_level;
+        assert!(block.evaluations() * 4 < finest, "{}", block.evaluations());
+        // everyone is back in sync at the end of the block
+        assert!(block.last_tick.iter().all(|tick| *tick == 1 << 8));
+    }
+
+    #[test]
+    fn block_steps_hold_the_orbits() {
+        let mut list_of_points = two_orbits();
+        let start_energy = total_energy(&list_of_points, &1.0);
+        let mut block = BlockSteps::new(0.05, 8, 0.05, 1.0);
+        // one period of the tight orbit
+        for _ in 0..60 {
+            block.step(&mut list_of_points, &mut direct);
+        }
+        let close = list_of_points[1]
+            .position
+            .get_distance(&list_of_points[0].position);
+        let far = list_of_points[2]
+            .position
+            .get_distance(&list_of_points[0].position);
+        assert!(Real::abs(close - 60.0) < 0.5, "{}", close);
+        assert!(Real::abs(far - 800.0) < 0.5, "{}", far);
+        let energy_error =
+            Real::abs((total_energy(&list_of_points, &1.0) - start_energy) / start_energy);
+        assert!(energy_error < 1e-2, "{}", energy_error);
+    }
+}
@@ -0,0 +1,295 @@
+// Collision response.
+//
+// Every particle is a solid disk of its radius. The tree is the broad phase
+// and hands back the pairs of disks that overlap (Tree::collision_pairs), then
+// each pair is resolved here. Pairs that are still closing get an impulse
+// along the line between their centers and every overlapping pair is pushed
+// apart so gravity never has to deal with two particles on top of each other.
+//
+// restitution is the fraction of the closing speed that is kept after a
+// bounce. 1 is a perfectly elastic bounce and 0 leaves the pair moving
+// together along the line between them.
+//
+// Instead of bouncing, overlapping particles can be merged into one body.
+// Mass and momentum are kept and the radius of the new body comes from a
+// density, which is mass per unit area as the particles are disks. In 3D the
+// particles are balls and the density is mass per unit volume.
+use crate::float::{consts::PI, Real};
+
+use crate::space::Space;
+use crate::tree::Particle;
+
+// what happens to a pair of particles that overlap
+#[derive(Debug, Copy, Clone)]
+pub enum Response {
+    // bounce apart keeping this fraction of the closing speed
+    Bounce(Real),
+    // stick together into one particle with a radius from this density
+    Merge(Real),
+}
+
+// radius of a disk with the given mass and density
+pub fn radius_for(mass: &Real, density: &Real) -> Real {
+    Real::sqrt(mass / (PI * density))
+}
+
+// radius of a ball with the given mass and density
+pub fn ball_radius_for(mass: &Real, density: &Real) -> Real {
+    Real::cbrt(3.0 * mass / (4.0 * PI * density))
+}
+
+// radius of a disk or a ball to match the space the particles are in
+fn body_radius<V: Space>(mass: &Real, density: &Real) -> Real {
+    if V::DIM == 2 {
+        radius_for(mass, density)
+    } else {
+        ball_radius_for(mass, density)
+    }
+}
+
+// resolve every overlapping pair. Returns the number of pairs that were
+// still closing and so needed an impulse
+pub fn resolve<V: Space>(
+    list_of_points: &mut [Particle<V>],
+    pairs: &[(usize, usize)],
+    restitution: &Real,
+) -> usize {
+    let mut bounces = 0;
+    for (a, b) in pairs {
+        if resolve_pair(list_of_points, *a, *b, restitution) {
+            bounces += 1;
+        }
+    }
+    bounces
+}
+
+// returns true if an impulse was applied
+fn resolve_pair<V: Space>(
+    list_of_points: &mut [Particle<V>],
+    a: usize,
+    b: usize,
+    restitution: &Real,
+) -> bool {
+    let first = list_of_points[a];
+    let second = list_of_points[b];
+    // an earlier pair may already have moved these apart
+    let distance = first.position.get_distance(&second.position);
+    let overlap = first.radius + second.radius - distance;
+    if overlap <= 0.0 {
+        return false;
+    }
+    // normal from a to b. Two particles on the same spot have no direction
+    // between them so just pick one
+    let normal = if distance > 0.0 {
+        (second.position - first.position) / distance
+    } else {
+        let mut normal = V::zero();
+        *normal.axis_mut(0) = 1.0;
+        normal
+    };
+    let inverse_a = 1.0 / first.mass;
+    let inverse_b = 1.0 / second.mass;
+    let inverse_total = inverse_a + inverse_b;
+
+    // push the pair apart so they just touch. The lighter particle moves more
+    let push_a = overlap * inverse_a / inverse_total;
+    let push_b = overlap * inverse_b / inverse_total;
+    list_of_points[a].position += -normal * push_a;
+    list_of_points[b].position += normal * push_b;
+
+    // speed of b relative to a along the normal. Positive means they are
+    // already moving apart and don't need an impulse
+    let closing = (second.velocity - first.velocity).dot(&normal);
+    if closing >= 0.0 {
+        return false;
+    }
+    let impulse = -(1.0 + restitution) * closing / inverse_total;
+    list_of_points[a].velocity += -normal * (impulse * inverse_a);
+    list_of_points[b].velocity += normal * (impulse * inverse_b);
+    true
+}
+
+// Merge every overlapping pair into one particle. Chains of overlapping
+// particles end up as a single particle. The particle with the lower index
+// takes in the other one and absorbed particles are removed from the list,
+// so the order of the particles that are left is kept. Returns the number of
+// particles removed
+pub fn merge<V: Space>(
+    list_of_points: &mut Vec<Particle<V>>,
+    pairs: &[(usize, usize)],
+    density: &Real,
+) -> usize {
+    if pairs.is_empty() {
+        return 0;
+    }
+    // absorbed_into[i] is the particle that took in particle i
+    let mut absorbed_into: Vec<Option<usize>> = vec![None; list_of_points.len()];
+    let mut removed = 0;
+    for (a, b) in pairs {
+        let a = survivor(&absorbed_into, *a);
+        let b = survivor(&absorbed_into, *b);
+        if a == b {
+            continue;
+        }
+        let (keep, gone) = (usize::min(a, b), usize::max(a, b));
+        list_of_points[keep] = combine(&list_of_points[keep], &list_of_points[gone], density);
+        absorbed_into[gone] = Some(keep);
+        removed += 1;
+    }
+    let mut index = 0;
+    list_of_points.retain(|_| {
+        index += 1;
+        absorbed_into[index - 1].is_none()
+    });
+    removed
+}
+
+// follow the chain of merges to the particle that holds index now
+fn survivor(absorbed_into: &[Option<usize>], mut index: usize) -> usize {
+    while let Some(next) = absorbed_into[index] {
+        index = next;
+    }
+    index
+}
+
+// one particle with the mass and momentum of both, sitting at their center
+// of mass
+pub fn combine<V: Space>(first: &Particle<V>, second: &Particle<V>, density: &Real) -> Particle<V> {
+    let mass = first.mass + second.mass;
+    let weighted = |a: &V, b: &V| (*a * first.mass + *b * second.mass) * (1.0 / mass);
+    Particle {
+        position: weighted(&first.position, &second.position),
+        velocity: weighted(&first.velocity, &second.velocity),
+        g_vector: weighted(&first.g_vector, &second.g_vector),
+        radius: body_radius::<V>(&mass, density),
+        softening: Real::max(first.softening, second.softening),
+        mass,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::space::Vector3;
+    use crate::tree::Vector;
+
+    fn disk(x: Real, velocity: Real, mass: Real) -> Particle {
+        Particle {
+            position: Vector { x, y: 0.0 },
+            velocity: Vector {
+                x: velocity,
+                y: 0.0,
+            },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 1.0,
+            softening: 0.0,
+            mass,
+        }
+    }
+
+    #[test]
+    fn elastic_head_on_swaps_velocities() {
+        let mut list_of_points = vec![disk(0.0, 1.0, 1.0), disk(1.5, -1.0, 1.0)];
+        assert_eq!(resolve(&mut list_of_points, &[(0, 1)], &1.0), 1);
+        assert!(Real::abs(list_of_points[0].velocity.x + 1.0) < 1e-6);
+        assert!(Real::abs(list_of_points[1].velocity.x - 1.0) < 1e-6);
+        // and they are pushed apart until they just touch
+        let distance = list_of_points[0]
+            .position
+            .get_distance(&list_of_points[1].position);
+        assert!(Real::abs(distance - 2.0) < 1e-6);
+    }
+
+    #[test]
+    fn inelastic_keeps_momentum() {
+        let mut list_of_points = vec![disk(0.0, 2.0, 3.0), disk(1.0, -1.0, 1.0)];
+        resolve(&mut list_of_points, &[(0, 1)], &0.0);
+        // with no restitution both move at the speed of the center of mass
+        let expected = (2.0 * 3.0 - 1.0) / 4.0;
+        assert!(Real::abs(list_of_points[0].velocity.x - expected) < 1e-6);
+        assert!(Real::abs(list_of_points[1].velocity.x - expected) < 1e-6);
+        // the heavy particle is moved less to separate them
+        assert!(
+            Real::abs(list_of_points[0].position.x) < Real::abs(list_of_points[1].position.x - 1.0)
+        );
+    }
+
+    #[test]
+    fn separating_pairs_are_not_kicked() {
+        let mut list_of_points = vec![disk(0.0, -1.0, 1.0), disk(1.0, 1.0, 1.0)];
+        assert_eq!(resolve(&mut list_of_points, &[(0, 1)], &1.0), 0);
+        assert_eq!(list_of_points[0].velocity.x, -1.0);
+        assert_eq!(list_of_points[1].velocity.x, 1.0);
+    }
+
+    #[test]
+    fn same_position_is_separated() {
+        let mut list_of_points = vec![disk(0.0, 0.0, 1.0), disk(0.0, 0.0, 1.0)];
+        resolve(&mut list_of_points, &[(0, 1)], &0.5);
+        let distance = list_of_points[0]
+            .position
+            .get_distance(&list_of_points[1].position);
+        assert!(Real::abs(distance - 2.0) < 1e-6);
+    }
+
+    #[test]
+    fn merge_keeps_mass_and_momentum() {
+        let mut list_of_points = vec![
+            disk(0.0, 2.0, 3.0),
+            disk(1.0, -1.0, 1.0),
+            disk(10.0, 0.0, 1.0),
+        ];
+        assert_eq!(merge(&mut list_of_points, &[(0, 1)], &1.0), 1);
+        assert_eq!(list_of_points.len(), 2);
+
+        let merged = list_of_points[0];
+        assert_eq!(merged.mass, 4.0);
+        assert_eq!(merged.velocity.x, (2.0 * 3.0 - 1.0) / 4.0);
+        assert_eq!(merged.position.x, 0.25);
+        assert_eq!(merged.radius, radius_for(&4.0, &1.0));
+        // the particle that wasn't touched is still there
+        assert_eq!(list_of_points[1].position.x, 10.0);
+    }
+
+    #[test]
+    fn merge_follows_chains() {
+        let mut list_of_points = vec![
+            disk(0.0, 0.0, 1.0),
+            disk(1.0, 0.0, 1.0),
+            disk(2.0, 0.0, 1.0),
+            disk(3.0, 0.0, 1.0),
+        ];
+        // 2 touches 1 and 3, and 1 touches 0 so all four become one
+        assert_eq!(
+            merge(&mut list_of_points, &[(1, 2), (0, 1), (2, 3)], &1.0),
+            3
+        );
+        assert_eq!(list_of_points.len(), 1);
+        assert_eq!(list_of_points[0].mass, 4.0);
+        assert_eq!(list_of_points[0].position.x, 1.5);
+    }
+
+    #[test]
+    fn radius_grows_with_mass() {
+        assert!(Real::abs(radius_for(&PI, &1.0) - 1.0) < 1e-6);
+        assert!(Real::abs(radius_for(&(4.0 * PI), &1.0) - 2.0) < 1e-6);
+    }
+
+    #[test]
+    fn merged_balls_use_volume() {
+        let ball = |x: Real| Particle {
+            position: Vector3 { x, y: 0.0, z: 1.0 },
+            velocity: Vector3::zero(),
+            g_vector: Vector3::zero(),
+            radius: 1.0,
+            softening: 0.0,
+            mass: 2.0 * PI / 3.0,
+        };
+        assert!(Real::abs(ball_radius_for(&(4.0 * PI / 3.0), &1.0) - 1.0) < 1e-6);
+        let mut list_of_points = vec![ball(0.0), ball(1.0)];
+        assert_eq!(merge(&mut list_of_points, &[(0, 1)], &1.0), 1);
+        // two halves of a unit ball make a unit ball
+        assert!(Real::abs(list_of_points[0].radius - 1.0) < 1e-6);
+        assert_eq!(list_of_points[0].position.z, 1.0);
+    }
+}
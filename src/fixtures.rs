@@ -0,0 +1,61 @@
+// Particle sets shared by the tests.
+//
+// The points are laid out on golden angle spirals, see float::GOLDEN_ANGLE,
+// so they are spread out without random numbers. Each set picks its own
+// distances and masses from the index of the point.
+use crate::float::{Real, GOLDEN_ANGLE};
+use crate::space::{Space, Vector3};
+use crate::tree::{Particle, Vector};
+
+// a particle at rest at position
+pub fn at_rest<V: Space>(position: V, mass: Real) -> Particle<V> {
+    Particle {
+        position,
+        velocity: V::zero(),
+        mass,
+        g_vector: V::zero(),
+        radius: 0.0,
+        softening: 0.0,
+    }
+}
+
+// count points in the plane, turning by the golden angle between each. Point
+// i sits distance(i) from the origin and weighs mass(i)
+pub fn spiral(
+    count: usize,
+    distance: impl Fn(usize) -> Real,
+    mass: impl Fn(usize) -> Real,
+) -> Vec<Particle> {
+    (0..count)
+        .map(|i| {
+            let position = Vector {
+                x: distance(i),
+                y: 0.0,
+            }
+            .rotate(&(i as Real * GOLDEN_ANGLE));
+            at_rest(position, mass(i))
+        })
+        .collect()
+}
+
+// count points spread over a sphere by the golden angle, each pushed out to
+// distance(i) from the origin so the sphere fills up into a ball
+pub fn ball(
+    count: usize,
+    distance: impl Fn(usize) -> Real,
+    mass: impl Fn(usize) -> Real,
+) -> Vec<Particle<Vector3>> {
+    (0..count)
+        .map(|i| {
+            let angle = i as Real * GOLDEN_ANGLE;
+            let height = 1.0 - 2.0 * (i as Real + 0.5) / count as Real;
+            let ring = Real::sqrt(1.0 - height * height);
+            let direction = Vector3 {
+                x: ring * Real::cos(angle),
+                y: ring * Real::sin(angle),
+                z: height,
+            };
+            at_rest(direction * distance(i), mass(i))
+        })
+        .collect()
+}
@@ -0,0 +1,28 @@
+// Floating point type of the simulation.
+//
+// Positions, velocities, masses and every constant of the physics are a
+// Real. It is f32 by default, which is fast and plenty for the window.
+// Building with the f64 feature switches the whole core to double precision
+// for long runs where f32 round-off builds up. The window only converts to
+// f32 when it draws.
+#[cfg(not(feature = "f64"))]
+pub type Real = f32;
+#[cfg(feature = "f64")]
+pub type Real = f64;
+
+#[cfg(not(feature = "f64"))]
+pub use std::f32::consts;
+#[cfg(feature = "f64")]
+pub use std::f64::consts;
+
+// pi * (3 - sqrt(5)). Turning by it between points on a spiral spreads them
+// evenly without them ever lining up, which lays out scenarios and test
+// particles without random numbers
+pub const GOLDEN_ANGLE: Real = 2.399_963;
+
+// narrow a value down for drawing, macroquad only takes f32. A no-op unless
+// the f64 feature is on
+#[allow(clippy::unnecessary_cast)]
+pub fn to_f32(value: &Real) -> f32 {
+    *value as f32
+}
@@ -0,0 +1,476 @@
+// Fast multipole method.
+//
+// Barnes-Hut works out the pull of a node on every particle one at a time
+// which is O(nlog(n)). The FMM instead lets whole nodes act on whole nodes.
+// Every node gets a multipole expansion of the particles inside it (upward
+// pass). Pairs of nodes that are far enough apart turn each other's multipole
+// into a local expansion around their own center (multipole to local).
+// Local expansions are then pushed down to the children (downward pass) and
+// evaluated at each particle. Nodes that are too close for that are split
+// until they are leaves whose particles are summed directly.
+//
+// The expansions are Cartesian Taylor series of 1 / r in powers of x and y,
+// which is the same 1 / r^2 force law the rest of the crate uses. Terms of
+// the potential are kept up to order and the force is its gradient, so the
+// force is one order lower. Order 1 is the monopole pull and order 3 adds the
+// quadrupole. The far field is plain gravity so softening only applies to the
+// particles that are summed directly.
+use crate::float::{consts::SQRT_2, Real};
+
+use crate::softening::Softening;
+use crate::solver::{ForceSolver, TreeBuilder};
+use crate::space::Space;
+use crate::tree::{Boundary, Particle, Tree, Vector, G};
+
+// The expansions are written in x and y so the FMM only runs on the quad
+// tree of Vector, never on the octree
+const CHILDREN: usize = <Vector as Space>::CHILDREN;
+
+// index of the x^a y^b term in a list of coefficients. Terms are ordered by
+// total order then by the power of y
+fn term(a: usize, b: usize) -> usize {
+    let order = a + b;
+    order * (order + 1) / 2 + b
+}
+
+// number of terms up to and including order
+fn term_count(order: usize) -> usize {
+    (order + 1) * (order + 2) / 2
+}
+
+// d^k / k! for k up to the length of out
+fn scaled_powers(d: &Real, inverse_factorial: &[Real], out: &mut [Real]) {
+    let mut power = 1.0;
+    for (k, value) in out.iter_mut().enumerate() {
+        *value = power * inverse_factorial[k];
+        power *= d;
+    }
+}
+
+// Every derivative of 1 / |r| up to order, d^(a + b) / dx^a dy^b, at r. Uses
+// the recurrence
+// r^2 D_n = -(2m - 1) / m * sum(n_i * r_i * D_(n - e_i))
+//           - (m - 1) / m * sum(n_i * (n_i - 1) * D_(n - 2 e_i))
+// where m = a + b
+fn derivatives(r: &Vector, order: usize, out: &mut [Real]) {
+    let inverse_squared = 1.0 / r.length_squared();
+    out[0] = Real::sqrt(inverse_squared);
+    for m in 1..=order {
+        let first = (2 * m - 1) as Real / m as Real;
+        let second = (m - 1) as Real / m as Real;
+        for b in 0..=m {
+            let a = m - b;
+            let mut value = 0.0;
+            if a >= 1 {
+                value -= first * a as Real * r.x * out[term(a - 1, b)];
+            }
+            if b >= 1 {
+                value -= first * b as Real * r.y * out[term(a, b - 1)];
+            }
+            if a >= 2 {
+                value -= second * (a * (a - 1)) as Real * out[term(a - 2, b)];
+            }
+            if b >= 2 {
+                value -= second * (b * (b - 1)) as Real * out[term(a, b - 2)];
+            }
+            out[term(a, b)] = value * inverse_squared;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Fmm {
+    // two nodes are far enough apart when the sum of their radii is less
+    // than theta times the distance between their centers
+    pub theta: Real,
+    // highest order kept in the expansions
+    pub order: usize,
+    pub builder: TreeBuilder<Vector>,
+    // expansion coefficients, term_count(order) for each node of the tree
+    multipoles: Vec<Real>,
+    locals: Vec<Real>,
+    // the tree's particles, in tree order, that the accelerations add up in
+    particles: Vec<Particle>,
+    // scratch for the node pairs still to visit
+    pairs: Vec<(usize, usize)>,
+}
+
+impl Fmm {
+    pub fn new(theta: Real, order: usize, boundary: Boundary, sorted_build: bool) -> Fmm {
+        Fmm {
+            theta,
+            order,
+            builder: TreeBuilder::new(boundary, sorted_build),
+            multipoles: vec![],
+            locals: vec![],
+            particles: vec![],
+            pairs: vec![],
+        }
+    }
+
+    pub fn out_of_bounds(&self) -> usize {
+        self.builder.out_of_bounds()
+    }
+
+    // work out the acceleration of every particle in the tree into
+    // self.particles
+    fn evaluate(&mut self, list_of_points: &mut [Particle]) {
+        let tree = self.builder.build(list_of_points);
+        let order = self.order;
+        let terms = term_count(order);
+        let mut inverse_factorial = vec![1.0; order + 1];
+        for n in 1..=order {
+            inverse_factorial[n] = inverse_factorial[n - 1] / n as Real;
+        }
+        self.particles.clear();
+        for index in 0..tree.particle_count() {
+            let mut particle = *tree.stored_particle(index).0;
+            particle.g_vector = Vector { x: 0.0, y: 0.0 };
+            self.particles.push(particle);
+        }
+
+        // upward pass. Children come after their parent in the arena so
+        // walking it backwards builds every child before its parent
+        let multipoles = &mut self.multipoles;
+        multipoles.clear();
+        multipoles.resize(tree.node_count() * terms, 0.0);
+        let mut sx = vec![0.0; order + 1];
+        let mut sy = vec![0.0; order + 1];
+        for node in (0..tree.node_count()).rev() {
+            let (center, _) = tree.node_square(node);
+            let (before, after) = multipoles.split_at_mut((node + 1) * terms);
+            let target = &mut before[node * terms..];
+            if let Some(first_child) = tree.first_child(node) {
+                for child in first_child..first_child + CHILDREN {
+                    if tree.node_mass(child) == 0.0 {
+                        continue;
+                    }
+                    // move the child's expansion over to this center
+                    let (child_center, _) = tree.node_square(child);
+                    let offset = center - child_center;
+                    scaled_powers(&offset.x, &inverse_factorial, &mut sx);
+                    scaled_powers(&offset.y, &inverse_factorial, &mut sy);
+                    let offset = (child - node - 1) * terms;
+                    let source = &after[offset..offset + terms];
+                    for m in 0..=order {
+                        for b in 0..=m {
+                            let a = m - b;
+                            let mut value = 0.0;
+                            for c in 0..=a {
+                                for d in 0..=b {
+                                    value += source[term(c, d)] * sx[a - c] * sy[b - d];
+                                }
+                            }
+                            target[term(a, b)] += value;
+                        }
+                    }
+                }
+            }
+            for index in tree.node_particle_indices(node) {
+                let particle = tree.stored_particle(index).0;
+                let offset = center - particle.position;
+                scaled_powers(&offset.x, &inverse_factorial, &mut sx);
+                scaled_powers(&offset.y, &inverse_factorial, &mut sy);
+                for m in 0..=order {
+                    for b in 0..=m {
+                        let a = m - b;
+                        target[term(a, b)] += particle.mass * sx[a] * sy[b];
+                    }
+                }
+            }
+        }
+
+        // walk pairs of nodes. Far apart pairs swap local expansions, close
+        // leaves are summed directly and anything else is split
+        let locals = &mut self.locals;
+        locals.clear();
+        locals.resize(tree.node_count() * terms, 0.0);
+        let mut derivative = vec![0.0; terms];
+        let softening = tree.softening();
+        let particles = &mut self.particles;
+        let pairs = &mut self.pairs;
+        pairs.clear();
+        pairs.push((0, 0));
+        while let Some((a, b)) = pairs.pop() {
+            if tree.node_mass(a) == 0.0 || tree.node_mass(b) == 0.0 {
+                continue;
+            }
+            if a == b {
+                match tree.first_child(a) {
+                    Some(first_child) => {
+                        for i in first_child..first_child + CHILDREN {
+                            for j in i..first_child + CHILDREN {
+                                pairs.push((i, j));
+                            }
+                        }
+                    }
+                    None => direct(tree, particles, a, a, &softening),
+                }
+                continue;
+            }
+            let (center_a, half_width_a) = tree.node_square(a);
+            let (center_b, half_width_b) = tree.node_square(b);
+            let r = center_b - center_a;
+            let distance = r.length();
+            if (half_width_a + half_width_b) * SQRT_2 < self.theta * distance {
+                // the local of b from the multipole of a uses the derivatives
+                // at b - a. Odd orders flip sign the other way round
+                derivatives(&r, order, &mut derivative);
+                multipole_to_local(multipoles, locals, &derivative, a, b, order, &1.0);
+                multipole_to_local(multipoles, locals, &derivative, b, a, order, &-1.0);
+                continue;
+            }
+            match (tree.first_child(a), tree.first_child(b)) {
+                (None, None) => {
+                    direct(tree, particles, a, b, &softening);
+                    direct(tree, particles, b, a, &softening);
+                }
+                (Some(first_child), None) => {
+                    pairs.extend((first_child..first_child + CHILDREN).map(|i| (i, b)))
+                }
+                (None, Some(first_child)) => {
+                    pairs.extend((first_child..first_child + CHILDREN).map(|j| (a, j)))
+                }
+                (Some(first_a), Some(first_b)) => {
+                    if half_width_a >= half_width_b {
+                        pairs.extend((first_a..first_a + CHILDREN).map(|i| (i, b)));
+                    } else {
+                        pairs.extend((first_b..first_b + CHILDREN).map(|j| (a, j)));
+                    }
+                }
+            }
+        }
+
+        // downward pass. Parents come first so their locals are complete
+        // before they are pushed down
+        for node in 0..tree.node_count() {
+            let (center, _) = tree.node_square(node);
+            if let Some(first_child) = tree.first_child(node) {
+                for child in first_child..first_child + CHILDREN {
+                    if tree.node_mass(child) == 0.0 {
+                        continue;
+                    }
+                    let (child_center, _) = tree.node_square(child);
+                    let offset = child_center - center;
+                    scaled_powers(&offset.x, &inverse_factorial, &mut sx);
+                    scaled_powers(&offset.y, &inverse_factorial, &mut sy);
+                    let (before, after) = locals.split_at_mut(child * terms);
+                    let source = &before[node * terms..(node + 1) * terms];
+                    let target = &mut after[..terms];
+                    for m in 0..=order {
+                        for b in 0..=m {
+                            let a = m - b;
+                            let mut value = 0.0;
+                            for c in a..=order {
+                                for d in b..=order - c {
+                                    value += source[term(c, d)] * sx[c - a] * sy[d - b];
+                                }
+                            }
+                            target[term(a, b)] += value;
+                        }
+                    }
+                }
+            }
+            // the acceleration is the gradient of the local expansion
+            let local = &locals[node * terms..(node + 1) * terms];
+            for index in tree.node_particle_indices(node) {
+                let point = &mut particles[index];
+                let offset = point.position - center;
+                scaled_powers(&offset.x, &inverse_factorial, &mut sx);
+                scaled_powers(&offset.y, &inverse_factorial, &mut sy);
+                for m in 0..order {
+                    for b in 0..=m {
+                        let a = m - b;
+                        let gradient = Vector {
+                            x: local[term(a + 1, b)],
+                            y: local[term(a, b + 1)],
+                        };
+                        point.g_vector += gradient * (G * sx[a] * sy[b]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// add the local expansion around the center of target made by the multipole
+// of source. derivative holds the derivatives of 1 / r at
+// sign * (target center - source center)
+fn multipole_to_local(
+    multipoles: &[Real],
+    locals: &mut [Real],
+    derivative: &[Real],
+    source: usize,
+    target: usize,
+    order: usize,
+    sign: &Real,
+) {
+    let terms = term_count(order);
+    let multipole = &multipoles[source * terms..(source + 1) * terms];
+    let local = &mut locals[target * terms..(target + 1) * terms];
+    for m in 0..=order {
+        for b in 0..=m {
+            let a = m - b;
+            let mut value = 0.0;
+            for k in 0..=order - m {
+                for d in 0..=k {
+                    let c = k - d;
+                    // derivatives of odd total order are odd in r
+                    let flip = if (m + k) % 2 == 1 { *sign } else { 1.0 };
+                    value += multipole[term(c, d)] * flip * derivative[term(a + c, b + d)];
+                }
+            }
+            local[term(a, b)] += value;
+        }
+    }
+}
+
+// pull of every particle in the leaf source on every particle in the leaf
+// target. Particles on the same spot don't pull on each other, which also
+// skips a particle pulling on itself
+fn direct(
+    tree: &Tree,
+    particles: &mut [Particle],
+    source: usize,
+    target: usize,
+    softening: &Softening,
+) {
+    for i in tree.node_particle_indices(target) {
+        for j in tree.node_particle_indices(source) {
+            let other = tree.stored_particle(j).0;
+            let point = &mut particles[i];
+            if point.position != other.position {
+                point.apply_softened_force(other, softening);
+            }
+        }
+    }
+}
+
+impl ForceSolver for Fmm {
+    fn name(&self) -> &'static str {
+        "fast multipole"
+    }
+
+    fn calc_gravity(&mut self, list_of_points: &mut [Particle]) {
+        self.evaluate(list_of_points);
+        for (index, particle) in self.particles.iter().enumerate() {
+            let point = &mut list_of_points[self.builder.tree().stored_particle(index).1];
+            point.g_vector += particle.g_vector;
+        }
+    }
+
+    // the FMM works out every particle at once so this saves nothing over
+    // calc_gravity, only the active particles are written back
+    fn calc_gravity_active(&mut self, list_of_points: &mut [Particle], active: &[usize]) {
+        self.evaluate(list_of_points);
+        let mut is_active = vec![false; list_of_points.len()];
+        for index in active {
+            is_active[*index] = true;
+        }
+        for (index, particle) in self.particles.iter().enumerate() {
+            let source = self.builder.tree().stored_particle(index).1;
+            if is_active[source] {
+                list_of_points[source].g_vector += particle.g_vector;
+            }
+        }
+    }
+
+    fn set_softening(&mut self, softening: Softening) {
+        self.builder.set_softening(softening);
+    }
+
+    fn last_tree(&self) -> Option<&Tree> {
+        Some(self.builder.tree())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::spiral;
+    use crate::solver::{relative_errors, DirectSum};
+
+    // a disk of particles with different masses
+    fn disk() -> Vec<Particle> {
+        let mut list_of_points = spiral(
+            400,
+            |i| 3.0 + Real::sqrt(i as Real) * 25.0,
+            |i| 1.0 + (i % 4) as Real,
+        );
+        // squashed into an ellipse
+        for point in &mut list_of_points {
+            point.position.y *= 0.6;
+        }
+        list_of_points
+    }
+
+    fn errors(order: usize, theta: Real) -> Vec<Real> {
+        let mut exact = disk();
+        DirectSum::new(Softening::None).calc_gravity(&mut exact);
+        let mut approx = disk();
+        Fmm::new(theta, order, Boundary::Fit, true).calc_gravity(&mut approx);
+        let mut errors = relative_errors(&exact, &approx);
+        errors.sort_by(Real::total_cmp);
+        errors
+    }
+
+    #[test]
+    fn derivatives_of_inverse_distance() {
+        let r = Vector { x: 3.0, y: -4.0 };
+        let mut out = vec![0.0; term_count(2)];
+        derivatives(&r, 2, &mut out);
+        let distance: Real = 5.0;
+        let close = |a: Real, b: Real| Real::abs(a - b) <= 1e-5 * Real::abs(b);
+        assert!(close(out[term(0, 0)], 1.0 / distance));
+        assert!(close(out[term(1, 0)], -r.x / distance.powi(3)));
+        assert!(close(out[term(0, 1)], -r.y / distance.powi(3)));
+        assert!(close(
+            out[term(2, 0)],
+            3.0 * r.x * r.x / distance.powi(5) - 1.0 / distance.powi(3)
+        ));
+        assert!(close(out[term(1, 1)], 3.0 * r.x * r.y / distance.powi(5)));
+        assert!(close(
+            out[term(0, 2)],
+            3.0 * r.y * r.y / distance.powi(5) - 1.0 / distance.powi(3)
+        ));
+    }
+
+    #[test]
+    fn fmm_matches_direct_sum() {
+        let errors = errors(6, 0.5);
+        assert!(
+            errors[errors.len() / 2] < 1e-3,
+            "{}",
+            errors[errors.len() / 2]
+        );
+        assert!(
+            errors[errors.len() * 99 / 100] < 1e-2,
+            "{}",
+            errors[errors.len() * 99 / 100]
+        );
+    }
+
+    #[test]
+    fn higher_order_is_more_accurate() {
+        let low = errors(1, 0.7);
+        let high = errors(5, 0.7);
+        assert!(high[high.len() / 2] * 10.0 < low[low.len() / 2]);
+    }
+
+    #[test]
+    fn fmm_active_matches_full() {
+        let mut fmm = Fmm::new(0.5, 6, Boundary::Fit, true);
+        let mut all = disk();
+        fmm.calc_gravity(&mut all);
+        let mut some = disk();
+        let active = [3, 100, 399];
+        fmm.calc_gravity_active(&mut some, &active);
+        for (i, (a, b)) in all.iter().zip(&some).enumerate() {
+            if active.contains(&i) {
+                assert_eq!(a.g_vector, b.g_vector);
+            } else {
+                assert_eq!(b.g_vector, Vector::zero());
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+// Time integrators that move the particles forward one step.
+//
+// An integrator doesn't know how gravity is worked out. It is handed a force
+// function that fills in g_vector with the acceleration of every particle at
+// its current position, so the same scheme can be driven by the tree or
+// anything else. The leapfrog and velocity Verlet end each step with the
+// acceleration at the new positions, so they keep it for the start of the
+// next step instead of working it out twice. Call reset when the particles
+// change between steps. The schemes work the same in 2D and 3D.
+use crate::float::Real;
+use crate::space::Space;
+use crate::tree::{Particle, Vector};
+
+// fills in g_vector for every particle. g_vector is cleared before the call
+pub type Forces<'a, V = Vector> = dyn FnMut(&mut [Particle<V>]) + 'a;
+
+pub trait Integrator<V: Space = Vector> {
+    // name shown when comparing schemes
+    fn name(&self) -> &'static str;
+
+    // advance every particle by delta_time
+    fn step(
+        &mut self,
+        list_of_points: &mut [Particle<V>],
+        delta_time: &Real,
+        forces: &mut Forces<V>,
+    );
+
+    // forget any accelerations kept from the last step so they are worked
+    // out again. Call this whenever particles are added, removed or moved
+    // by hand
+    fn reset(&mut self) {}
+}
+
+// every integrator, in the order the window cycles through them
+pub fn all<V: Space>() -> Vec<Box<dyn Integrator<V>>> {
+    vec![
+        Box::new(SemiImplicitEuler),
+        Box::new(Leapfrog::default()),
+        Box::new(VelocityVerlet::default()),
+        Box::new(RungeKutta4),
+        Box::new(Yoshida4),
+    ]
+}
+
+// clear the accumulated acceleration and work it out again
+fn accelerate<V: Space>(list_of_points: &mut [Particle<V>], forces: &mut Forces<V>) {
+    for point in list_of_points.iter_mut() {
+        point.g_vector = V::zero();
+    }
+    forces(list_of_points);
+}
+
+// work out the acceleration unless the last step left it behind. Every
+// particle moves during a step so the kept acceleration is only good until
+// the next drift
+fn accelerate_once<V: Space>(
+    list_of_points: &mut [Particle<V>],
+    forces: &mut Forces<V>,
+    forces_ready: &mut bool,
+) {
+    if !*forces_ready {
+        accelerate(list_of_points, forces);
+    }
+    *forces_ready = false;
+}
+
+// update the velocity from the current acceleration
+fn kick<V: Space>(list_of_points: &mut [Particle<V>], delta_time: Real) {
+    for point in list_of_points {
+        point.velocity += point.g_vector * delta_time;
+    }
+}
+
+// update the position from the current velocity
+fn drift<V: Space>(list_of_points: &mut [Particle<V>], delta_time: Real) {
+    for point in list_of_points {
+        point.position += point.velocity * delta_time;
+    }
+}
+
+// Kinetic plus gravitational potential energy of the whole system. Summed
+// over every pair so it is only meant for checking how well a scheme holds
+// energy on small systems
+pub fn total_energy<V: Space>(list_of_points: &[Particle<V>], g: &Real) -> Real {
+    let mut energy = 0.0;
+    for (i, point) in list_of_points.iter().enumerate() {
+        energy += 0.5 * point.mass * point.velocity.dot(&point.velocity);
+        for other in &list_of_points[i + 1..] {
+            let distance = point.position.get_distance(&other.position);
+            if distance > 0.0 {
+                energy -= g * point.mass * other.mass / distance;
+            }
+        }
+    }
+    energy
+}
+
+// The scheme the simulation started with. Kick the velocity with the current
+// acceleration then drift with the new velocity. First order but symplectic
+pub struct SemiImplicitEuler;
+
+impl<V: Space> Integrator<V> for SemiImplicitEuler {
+    fn name(&self) -> &'static str {
+        "semi-implicit euler"
+    }
+
+    fn step(
+        &mut self,
+        list_of_points: &mut [Particle<V>],
+        delta_time: &Real,
+        forces: &mut Forces<V>,
+    ) {
+        accelerate(list_of_points, forces);
+        kick(list_of_points, *delta_time);
+        drift(list_of_points, *delta_time);
+    }
+}
+
+// Kick-drift-kick leapfrog. Half a kick, a full drift then another half kick
+// with the acceleration at the new position. Second order and symplectic
+#[derive(Default)]
+pub struct Leapfrog {
+    // g_vector holds the acceleration at the current positions
+    forces_ready: bool,
+}
+
+impl<V: Space> Integrator<V> for Leapfrog {
+    fn name(&self) -> &'static str {
+        "leapfrog (kdk)"
+    }
+
+    fn step(
+        &mut self,
+        list_of_points: &mut [Particle<V>],
+        delta_time: &Real,
+        forces: &mut Forces<V>,
+    ) {
+        accelerate_once(list_of_points, forces, &mut self.forces_ready);
+        kick(list_of_points, delta_time / 2.0);
+        drift(list_of_points, *delta_time);
+        accelerate(list_of_points, forces);
+        kick(list_of_points, delta_time / 2.0);
+        self.forces_ready = true;
+    }
+
+    fn reset(&mut self) {
+        self.forces_ready = false;
+    }
+}
+
+// Velocity Verlet. The position is moved with the old velocity and
+// acceleration, then the velocity is moved with the average of the old and
+// new acceleration. Second order
+#[derive(Default)]
+pub struct VelocityVerlet {
+    // g_vector holds the acceleration at the current positions
+    forces_ready: bool,
+}
+
+impl<V: Space> Integrator<V> for VelocityVerlet {
+    fn name(&self) -> &'static str {
+        "velocity verlet"
+    }
+
+    fn step(
+        &mut self,
+        list_of_points: &mut [Particle<V>],
+        delta_time: &Real,
+        forces: &mut Forces<V>,
+    ) {
+        accelerate_once(list_of_points, forces, &mut self.forces_ready);
+        let old_acceleration: Vec<V> = list_of_points.iter().map(|point| point.g_vector).collect();
+        for point in list_of_points.iter_mut() {
+            point.position +=
+                point.velocity * *delta_time + point.g_vector * (0.5 * delta_time * delta_time);
+        }
+        accelerate(list_of_points, forces);
+        for (point, old) in list_of_points.iter_mut().zip(&old_acceleration) {
+            point.velocity += (*old + point.g_vector) * (0.5 * delta_time);
+        }
+        self.forces_ready = true;
+    }
+
+    fn reset(&mut self) {
+        self.forces_ready = false;
+    }
+}
+
+// Classic fourth order Runge-Kutta. Four force evaluations per step at
+// trial states. Very accurate for a single step but not symplectic so energy
+// slowly drifts over long runs
+pub struct RungeKutta4;
+
+impl<V: Space> Integrator<V> for RungeKutta4 {
+    fn name(&self) -> &'static str {
+        "runge-kutta 4"
+    }
+
+    fn step(
+        &mut self,
+        list_of_points: &mut [Particle<V>],
+        delta_time: &Real,
+        forces: &mut Forces<V>,
+    ) {
+        let start: Vec<Particle<V>> = list_of_points.to_vec();
+        let mut trial = start.clone();
+        // sum of the weighted derivatives of position and velocity
+        let mut position_sum = vec![V::zero(); start.len()];
+        let mut velocity_sum = vec![V::zero(); start.len()];
+
+        // each stage: the fraction of the step the trial state sits at, and the
+        // weight of the derivative found there
+        let stages = [(0.0, 1.0), (0.5, 2.0), (0.5, 2.0), (1.0, 1.0)];
+        for (stage, (fraction, weight)) in stages.iter().enumerate() {
+            if stage > 0 {
+                // move the trial state along the derivative of the last stage
+                for (point, origin) in trial.iter_mut().zip(&start) {
+                    let step = fraction * delta_time;
+                    point.position = origin.position + point.velocity * step;
+                    point.velocity = origin.velocity + point.g_vector * step;
+                }
+            }
+            accelerate(&mut trial, forces);
+            for (i, point) in trial.iter().enumerate() {
+                position_sum[i] += point.velocity * *weight;
+                velocity_sum[i] += point.g_vector * *weight;
+            }
+        }
+
+        for (i, point) in list_of_points.iter_mut().enumerate() {
+            point.position = start[i].position + position_sum[i] * (delta_time / 6.0);
+            point.velocity = start[i].velocity + velocity_sum[i] * (delta_time / 6.0);
+            point.g_vector = trial[i].g_vector;
+        }
+    }
+}
+
+// Fourth order Yoshida. Three leapfrog steps with weights chosen so the
+// errors cancel, one of which runs backwards in time. Symplectic like the
+// leapfrog but fourth order, for three force evaluations per step
+pub struct Yoshida4;
+
+impl<V: Space> Integrator<V> for Yoshida4 {
+    fn name(&self) -> &'static str {
+        "yoshida 4"
+    }
+
+    fn step(
+        &mut self,
+        list_of_points: &mut [Particle<V>],
+        delta_time: &Real,
+        forces: &mut Forces<V>,
+    ) {
+        let cube_root = Real::cbrt(2.0);
+        let w1 = 1.0 / (2.0 - cube_root);
+        let w0 = -cube_root / (2.0 - cube_root);
+        let drifts = [w1 / 2.0, (w0 + w1) / 2.0, (w0 + w1) / 2.0, w1 / 2.0];
+        let kicks = [w1, w0, w1];
+
+        for (i, kick_weight) in kicks.iter().enumerate() {
+            drift(list_of_points, drifts[i] * delta_time);
+            accelerate(list_of_points, forces);
+            kick(list_of_points, kick_weight * delta_time);
+        }
+        drift(list_of_points, drifts[3] * delta_time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::float::consts;
+    use crate::softening::Softening;
+    use crate::solver::{DirectSum, ForceSolver};
+    use crate::space::Vector3;
+
+    // exact pairwise gravity for the tests
+    fn direct<V: Space>(list_of_points: &mut [Particle<V>]) {
+        DirectSum::new(Softening::None).calc_gravity(list_of_points);
+    }
+
+    // a light particle on a circular orbit around a heavy one. The orbit
+    // has a radius of 100, a speed of 100 and a period of 2 pi
+    fn circular_orbit() -> Vec<Particle> {
+        vec![
+            Particle {
+                position: Vector { x: 0.0, y: 0.0 },
+                velocity: Vector { x: 0.0, y: 0.0 },
+                g_vector: Vector { x: 0.0, y: 0.0 },
+                radius: 0.0,
+                softening: 0.0,
+                mass: 1_000_000.0,
+            },
+            Particle {
+                position: Vector { x: 100.0, y: 0.0 },
+                velocity: Vector { x: 0.0, y: 100.0 },
+                g_vector: Vector { x: 0.0, y: 0.0 },
+                radius: 0.0,
+                softening: 0.0,
+                mass: 1.0,
+            },
+        ]
+    }
+
+    // run for an orbit and return how far the light particle ended from where
+    // the exact solution puts it and the relative change in energy
+    fn run_orbit(integrator: &mut dyn Integrator, delta_time: Real) -> (Real, Real) {
+        let mut list_of_points = circular_orbit();
+        let start_energy = total_energy(&list_of_points, &1.0);
+        let steps = (2.0 * consts::PI / delta_time).round() as usize;
+        for _ in 0..steps {
+            integrator.step(&mut list_of_points, &delta_time, &mut direct);
+        }
+        let time = steps as Real * delta_time;
+        let exact = Vector {
+            x: 100.0 * Real::cos(time),
+            y: 100.0 * Real::sin(time),
+        };
+        let error = list_of_points[1].position.get_distance(&exact);
+        let energy_error =
+            Real::abs((total_energy(&list_of_points, &1.0) - start_energy) / start_energy);
+        (error, energy_error)
+    }
+
+    #[test]
+    fn integrators_close_the_orbit() {
+        for mut integrator in all() {
+            let (error, energy_error) = run_orbit(integrator.as_mut(), 0.05);
+            assert!(error < 2.0, "{} is {} off", integrator.name(), error);
+            assert!(
+                energy_error < 1e-4,
+                "{} energy error {}",
+                integrator.name(),
+                energy_error
+            );
+        }
+    }
+
+    #[test]
+    fn higher_order_is_more_accurate() {
+        let (euler, _) = run_orbit(&mut SemiImplicitEuler, 0.05);
+        let (leapfrog, _) = run_orbit(&mut Leapfrog::default(), 0.05);
+        let (verlet, _) = run_orbit(&mut VelocityVerlet::default(), 0.05);
+        let (rk4, _) = run_orbit(&mut RungeKutta4, 0.05);
+        let (yoshida, _) = run_orbit(&mut Yoshida4, 0.05);
+        assert!(leapfrog < euler);
+        assert!(verlet < euler);
+        // the fourth order schemes should be far ahead of the second order ones
+        assert!(rk4 * 10.0 < leapfrog);
+        assert!(yoshida * 10.0 < leapfrog);
+    }
+
+    #[test]
+    fn leapfrog_matches_velocity_verlet() {
+        // the two schemes are the same algorithm written differently
+        let mut a = circular_orbit();
+        let mut b = circular_orbit();
+        let mut leapfrog = Leapfrog::default();
+        let mut verlet = VelocityVerlet::default();
+        for _ in 0..100 {
+            leapfrog.step(&mut a, &0.01, &mut direct);
+            verlet.step(&mut b, &0.01, &mut direct);
+        }
+        let distance = a[1].position.get_distance(&b[1].position);
+        assert!(distance < 1e-3, "{}", distance);
+    }
+
+    #[test]
+    fn second_order_schemes_reuse_the_forces() {
+        let integrators: [Box<dyn Integrator>; 2] = [
+            Box::new(Leapfrog::default()),
+            Box::new(VelocityVerlet::default()),
+        ];
+        for mut integrator in integrators {
+            let mut list_of_points = circular_orbit();
+            let mut calls = 0;
+            let mut counted = |list_of_points: &mut [Particle]| {
+                calls += 1;
+                direct(list_of_points);
+            };
+            // two passes on the first step then one per step
+            for _ in 0..10 {
+                integrator.step(&mut list_of_points, &0.01, &mut counted);
+            }
+            // and two again once the particles were moved by hand
+            integrator.reset();
+            list_of_points[1].position.x += 1.0;
+            integrator.step(&mut list_of_points, &0.01, &mut counted);
+            assert_eq!(calls, 13, "{}", integrator.name());
+        }
+    }
+
+    #[test]
+    fn integrators_work_in_3d() {
+        // the same orbit tipped out of the plane so every axis is used
+        let (sin, cos) = Real::sin_cos(0.6);
+        let mut list_of_points: Vec<Particle<Vector3>> = circular_orbit()
+            .iter()
+            .map(|point| Particle {
+                position: Vector3 {
+                    x: point.position.x,
+                    y: point.position.y * cos,
+                    z: point.position.y * sin,
+                },
+                velocity: Vector3 {
+                    x: point.velocity.x,
+                    y: point.velocity.y * cos,
+                    z: point.velocity.y * sin,
+                },
+                g_vector: Vector3::zero(),
+                radius: 0.0,
+                softening: 0.0,
+                mass: point.mass,
+            })
+            .collect();
+        let start_energy = total_energy(&list_of_points, &1.0);
+        // a quarter of the way round the orbit
+        let steps = (consts::PI / 2.0 / 0.01).round() as usize;
+        for _ in 0..steps {
+            Yoshida4.step(&mut list_of_points, &0.01, &mut direct);
+        }
+        let time = steps as Real * 0.01;
+        let exact = Vector3 {
+            x: 100.0 * Real::cos(time),
+            y: 100.0 * Real::sin(time) * cos,
+            z: 100.0 * Real::sin(time) * sin,
+        };
+        let error = list_of_points[1].position.get_distance(&exact);
+        assert!(error < 0.1, "{}", error);
+        let energy_error =
+            Real::abs((total_energy(&list_of_points, &1.0) - start_energy) / start_energy);
+        assert!(energy_error < 1e-4, "{}", energy_error);
+    }
+}
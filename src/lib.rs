@@ -0,0 +1,31 @@
+// Gravity simulation of particles that are solid disks, or balls in 3D.
+//
+// After every step the tree is rebuilt and used to find the particles that
+// overlap, which are then bounced off each other or merged. This means
+// gravity never has to be capped as particles never get close enough to
+// produce super large G forces. Gravity can also be softened so close passes
+// stay smooth, see the softening module.
+//
+// Simulation ties the pieces together. The window app in main.rs only draws
+// it and maps keys to its settings, and the headless runner in
+// src/bin/headless.rs runs it with no display and writes the results to disk.
+pub mod accuracy;
+pub mod block;
+pub mod collision;
+#[cfg(test)]
+mod fixtures;
+pub mod float;
+pub mod fmm;
+pub mod integrator;
+pub mod morton;
+pub mod output;
+pub mod scenario;
+pub mod simulation;
+pub mod softening;
+pub mod solver;
+pub mod space;
+pub mod timestep;
+pub mod tree;
+pub mod units;
+
+pub use simulation::{Simulation, SimulationSpace, Solver, Solver3d};
@@ -1,137 +1,280 @@
-use std::{f32::consts::PI, vec};
+use std::vec;
 
 use macroquad::prelude::*;
-// use std::time::Instant;
-pub mod tree;
+use rusty_orbit::float::{self, Real};
+use rusty_orbit::scenario::{self, load_scenario, DENSITY, SCENARIOS};
+use rusty_orbit::{
+    accuracy, collision, integrator, softening, timestep, tree, Simulation, SimulationSpace,
+};
 
-// current problem
-// the tree and the display are in a stablish state yet collisions
-// are not handled well. Currently this is "handled" by capping
-// the max gravitational force that can be applied. This isn't
-// my end goal with this project as I would like to see objects
-// "sick" together due to gravity.
+// The window app. The physics and the demo scenarios live in the library,
+// see Simulation and the scenario module, and this only draws them and maps
+// keys to the settings. Scenarios can be given in real units, see the units
+// module. They are converted into units where G = 1 before the simulation
+// starts and the view is scaled so the whole scenario fits on the screen
 
-// current plan for mixing this two is that particles accumulate force
-// vectors. One being the gravitational force then collision force. (hoping to reuse
-// the tree for finding collision pairs). This should mean I don't need to
-// cap forces as they will never get close enough to produce super large G forces
-
-fn update_all_positions(list_of_points: &mut Vec<tree::Particle>, delta_time: &f32) {
-    let mut tree = tree::Tree::new();
+// distance from the origin to the furthest particle along either axis, used
+// to fit the scenario to the screen
+fn extent(list_of_points: &[tree::Particle]) -> Real {
+    list_of_points
+        .iter()
+        .map(|point| Real::max(Real::abs(point.position.x), Real::abs(point.position.y)))
+        .fold(0.0, Real::max)
+}
 
-    // add to the tree. A copy will happen here which is required.
-    // The tree needs to be constant as the list of points vector
-    // is being updated
-    for point in &*list_of_points {
-        tree.append_node(&point);
+// the fixed step to switch to from an adaptive one
+fn fixed_step(adaptive_step: &timestep::StepSize) -> timestep::StepSize {
+    match adaptive_step {
+        timestep::StepSize::Adaptive(adaptive) => {
+            timestep::StepSize::Fixed(adaptive.max_step / 5.0)
+        }
+        fixed => *fixed,
     }
-    // calculate the average mass for each node
-    tree.build_average_mass();
-
-    // big scary O(nlog(n)) apply forces calc time.
-    // Traverse the tree and accumulate force vectors of gravity
-    tree.calc_gravity_vector(list_of_points, delta_time);
+}
 
-    // now update position
-    for point in list_of_points {
-        point.update_velocity(&0.01);
-        point.update_position(&0.01);
+fn main() {
+    // print how accurate the tree is for each scenario instead of opening
+    // the window
+    if std::env::args().any(|arg| arg == "--accuracy") {
+        print_accuracy();
+        return;
     }
+    macroquad::Window::new("Rusty orbit", run_window());
 }
 
-fn get_number_particles(layer: &i32) -> i32 {
-    if layer == &1 {
-        return 1;
+// compare the tree against the direct sum over a range of theta, for the
+// demo scenarios and a 3D cluster built on the octree
+fn print_accuracy() {
+    let thetas = [0.1, 0.2, 0.3, 0.5, 0.7, 1.0, 1.5];
+    for (name, units, scenario) in &SCENARIOS {
+        let list_of_points = load_scenario(units, *scenario, &DENSITY);
+        println!("{}", name);
+        println!(
+            "{}",
+            accuracy::accuracy_report(&list_of_points, &thetas, &softening::Softening::None)
+        );
     }
-    let large_diameter = 60 * layer + 60;
-    (PI / (f32::asin(60.0 / large_diameter as f32))) as i32
+    println!("3D cluster");
+    println!(
+        "{}",
+        accuracy::accuracy_report(
+            &scenario::build_cluster_3d(),
+            &thetas,
+            &softening::Softening::None
+        )
+    );
 }
 
-fn map_to_position(layer: &i32, delta_theta: &f32, center: &tree::Vector) -> tree::Vector {
-    if *layer == 1 {
-        return *center;
-    }
+async fn run_window() {
+    let density = DENSITY;
+    let scenarios = SCENARIOS.map(|(_, units, scenario)| (units, scenario));
+    let mut current_scenario = 0;
+    let (mut units, scenario) = scenarios[current_scenario];
+    let mut simulation = Simulation::new(load_scenario(&units, scenario, &density));
+    // the view is sized when a scenario starts so it fills the screen
+    // whatever units it is in
+    let mut view_extent = extent(&simulation.particles);
 
-    let large_radius = 30 * layer + 30;
-    return tree::Vector {
-        x: center.x + (large_radius as f32 * f32::cos(*delta_theta)),
-        y: center.y + (large_radius as f32 * f32::sin(*delta_theta)),
-    };
-}
+    // press D to cycle through the solvers. The Barnes-Hut tree is the
+    // default. The direct sum works out every pair exactly, which is only
+    // usable for small systems but it is the reference the others are
+    // measured against. The fast multipole method lets whole nodes act on
+    // each other which is O(n) for large systems
+    let solvers = <tree::Vector as SimulationSpace>::SOLVERS;
+    let mut current_solver = 0;
+    // particles that touch bounce apart keeping half of their closing speed.
+    // Press M to have them merge into one body instead
+    let bounce = simulation.response;
+    let merge = collision::Response::Merge(density);
+    // press G to switch how gravity is softened for close passes
+    let softenings = [
+        softening::Softening::None,
+        softening::Softening::Plummer(10.0),
+        softening::Softening::Spline(30.0),
+        softening::Softening::Adaptive {
+            neighbours: 4,
+            scale: 1.0,
+        },
+    ];
+    let mut current_softening = 0;
 
-fn build_mass(
-    num_particles: &i32,
-    center: &tree::Vector,
-    velocity: &tree::Vector,
-) -> Vec<tree::Particle> {
-    let mut layer = 1;
-    let mut num_placed = 0;
-    let mut mass: Vec<tree::Particle> = vec![];
+    // press I to restart the scenario with the next integrator so the
+    // schemes can be compared on the same starting state
+    let integrator_count = integrator::all::<tree::Vector>().len();
+    let mut current_integrator = 0;
+    let mut start_energy = simulation.total_energy();
 
-    'outer: loop {
-        let current_layer_num = get_number_particles(&layer);
-        for i in 0..current_layer_num {
-            let delta_theta = (360 as f32 / current_layer_num as f32) * i as f32;
-            let position = map_to_position(&layer, &delta_theta, center);
-            // let velocity = tree::Vector{
-            //     x: - center.y - position.y,
-            //     y: center.x - position.x
-            // }.normialize().multiple(&10.0);
-            // // add particle to mass
-            // let velocity = tree::Vector {
-            //     x: position.x - ((center.x - position.x) * (1.0 / normal_mag)),
-            //     y: position.y + ((center.y - position.y) * (1.0 / normal_mag)),
-            // };
+    // By default the simulation follows the real frame time so its speed
+    // doesn't depend on the frame rate. Press T to switch to one step per frame
+    let mut frame_steps = timestep::FrameSteps::real_time();
+    // Steps are sized from the largest acceleration so close encounters get
+    // small steps. Press A to switch between that and a fixed step of a fifth
+    // of the largest adaptive step. Both are sized to the scenario
+    let mut adaptive_step = simulation.step_size;
 
-            mass.push(tree::Particle {
-                position,
-                velocity: *velocity,
-                mass: 100.0,
-                g_vector: tree::Vector { x: 0.0, y: 0.0 },
-            });
-            num_placed += 1;
-            // if we are done then exit
-            if num_placed == *num_particles {
-                break 'outer;
+    loop {
+        let mut restart = false;
+        if is_key_pressed(KeyCode::I) {
+            current_integrator = (current_integrator + 1) % integrator_count;
+            restart = true;
+        }
+        if is_key_pressed(KeyCode::S) {
+            current_scenario = (current_scenario + 1) % scenarios.len();
+            restart = true;
+        }
+        if restart {
+            simulation.integrator = integrator::all().remove(current_integrator);
+            let scenario;
+            (units, scenario) = scenarios[current_scenario];
+            simulation.reset(load_scenario(&units, scenario, &density));
+            let fixed = matches!(simulation.step_size, timestep::StepSize::Fixed(_));
+            simulation.scale_steps();
+            adaptive_step = simulation.step_size;
+            if fixed {
+                simulation.step_size = fixed_step(&adaptive_step);
             }
+            view_extent = extent(&simulation.particles);
+            start_energy = simulation.total_energy();
+        }
+        if is_key_pressed(KeyCode::G) {
+            current_softening = (current_softening + 1) % softenings.len();
+            simulation.set_softening(softenings[current_softening]);
+        }
+        if is_key_pressed(KeyCode::M) {
+            simulation.response = match simulation.response {
+                collision::Response::Bounce(_) => merge,
+                collision::Response::Merge(_) => bounce,
+            };
+        }
+        if is_key_pressed(KeyCode::D) {
+            current_solver = (current_solver + 1) % solvers.len();
+            simulation.solver = solvers[current_solver];
+        }
+        // give every particle its own power of two step instead. The
+        // integrator and step size are ignored while this is on
+        if is_key_pressed(KeyCode::B) {
+            simulation.block_steps = !simulation.block_steps;
+            simulation.block.reset();
         }
-        layer += 1;
-    }
-    return mass;
-}
-
-#[macroquad::main("Rusty orbit")]
-async fn main() {
-    // build two masses here that have opposite positions and velocities
-    // This allows a demo of the two counter rotating
-    let mut list_of_points = build_mass(
-        &5,
-        &tree::Vector { x: -160.0, y: 0.0 },
-        &tree::Vector { x: 0.0, y: -10.0 },
-    );
-    let mut another_list = build_mass(
-        &5,
-        &tree::Vector { x: 160.0, y: 0.0 },
-        &tree::Vector { x: 0.0, y: 10.0 },
-    );
 
-    list_of_points.append(&mut another_list);
+        if is_key_pressed(KeyCode::T) {
+            frame_steps = match frame_steps {
+                timestep::FrameSteps::Fixed { .. } => timestep::FrameSteps::real_time(),
+                timestep::FrameSteps::RealTime { .. } => timestep::FrameSteps::fixed(),
+            };
+        }
+        if is_key_pressed(KeyCode::A) {
+            simulation.step_size = match simulation.step_size {
+                timestep::StepSize::Fixed(_) => adaptive_step,
+                timestep::StepSize::Adaptive(_) => fixed_step(&adaptive_step),
+            };
+        }
 
-    loop {
         //physics update
-        // delta time isn't working right now since it is being rounded to 0
-        // let delta_time = current_time - Instant::now();
-        // current_time = Instant::now();
-        update_all_positions(&mut list_of_points, &0.01);
-
+        frame_steps.start_frame(&(get_frame_time() as Real));
+        loop {
+            let delta_time = simulation.next_step_size();
+            if !frame_steps.next_step(&delta_time) {
+                break;
+            }
+            simulation.step_by(&delta_time);
+        }
         //graphics update
         clear_background(BLACK);
-        for point in &list_of_points {
-            draw_circle(
-                screen_width() / 2.0 + point.position.x,
-                screen_height() / 2.0 + point.position.y,
-                30.0,
-                BLUE,
+        let screen_center = vec2(screen_width() / 2.0, screen_height() / 2.0);
+        // pixels per unit of length, leaving a margin around the scenario
+        let scale = if view_extent > 0.0 {
+            0.45 * screen_width().min(screen_height()) / float::to_f32(&view_extent)
+        } else {
+            1.0
+        };
+        for point in &simulation.particles {
+            // the physics may run in f64 but macroquad draws in f32. Bodies
+            // too small to see at this scale are still drawn a pixel wide
+            let screen = screen_center + Vec2::from(point.position) * scale;
+            let radius = (float::to_f32(&point.radius) * scale).max(1.0);
+            draw_circle(screen.x, screen.y, radius, BLUE);
+        }
+        let energy = simulation.total_energy();
+        draw_text(
+            format!(
+                "{} (I to switch)  {} (T to switch)  energy change {:.3e}",
+                simulation.integrator.name(),
+                match frame_steps {
+                    timestep::FrameSteps::Fixed { .. } => "step per frame",
+                    timestep::FrameSteps::RealTime { .. } => "real time",
+                },
+                (energy - start_energy) / Real::abs(start_energy)
+            ),
+            10.0,
+            20.0,
+            20.0,
+            WHITE,
+        );
+        let step_text = if simulation.block_steps {
+            // count how many particles sit on each level
+            let mut counts = vec![0; simulation.block.max_level as usize + 1];
+            for level in simulation.block.levels() {
+                counts[*level as usize] += 1;
+            }
+            format!(
+                "block steps of {:.4} (B to switch) particles per level {:?}",
+                simulation.block.max_step, counts
+            )
+        } else {
+            format!(
+                "{} step {:.4} (A to switch, B for block steps)",
+                match simulation.step_size {
+                    timestep::StepSize::Fixed(_) => "fixed",
+                    timestep::StepSize::Adaptive(_) => "adaptive",
+                },
+                simulation.last_step
+            )
+        };
+        draw_text(&step_text, 10.0, 40.0, 20.0, WHITE);
+        draw_text(
+            format!(
+                "{} in {} (S for next scenario)  time {:.3}",
+                SCENARIOS[current_scenario].0,
+                units.name(),
+                units.from_simulation_time(&simulation.time)
+            ),
+            10.0,
+            80.0,
+            20.0,
+            WHITE,
+        );
+        draw_text(
+            format!(
+                "{} particles  {} (D to switch)  {} (M to switch)  softening {} (G to switch)",
+                simulation.particles.len(),
+                simulation.solver_name(),
+                match simulation.response {
+                    collision::Response::Bounce(_) => "bouncing",
+                    collision::Response::Merge(_) => "merging",
+                },
+                match simulation.softening() {
+                    softening::Softening::None => "off",
+                    softening::Softening::Plummer(_) => "Plummer",
+                    softening::Softening::Spline(_) => "spline",
+                    softening::Softening::Adaptive { .. } => "adaptive",
+                }
+            ),
+            10.0,
+            60.0,
+            20.0,
+            WHITE,
+        );
+        if simulation.barnes_hut.out_of_bounds() > 0 {
+            draw_text(
+                format!(
+                    "{} particles outside of the boundary",
+                    simulation.barnes_hut.out_of_bounds()
+                ),
+                10.0,
+                100.0,
+                20.0,
+                RED,
             );
         }
         next_frame().await
@@ -0,0 +1,169 @@
+// Morton (Z-order) keys for positions inside a square or cube.
+//
+// The square is split into a 2^bits by 2^bits grid and the x and y cell
+// numbers are interleaved bit by bit, x in the even bits and y in the odd
+// bits. Sorting by the key walks the cells in Z order, which is the same
+// order a depth first walk of the quad tree visits its leaves, so
+// particles that are close in space end up close in memory. A cube is the
+// same with the x, y and z cell numbers in every third bit.
+//
+// Each group of DIM bits, starting at the top, is the child a point falls in
+// at that depth of the tree. See digit for how to read them back.
+use crate::float::Real;
+use crate::space::Space;
+
+// spread the low 32 bits of value out so there is a zero between each bit
+pub fn spread_bits(value: u32) -> u64 {
+    let mut value = value as u64;
+    value = (value | (value << 16)) & 0x0000_FFFF_0000_FFFF;
+    value = (value | (value << 8)) & 0x00FF_00FF_00FF_00FF;
+    value = (value | (value << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    value = (value | (value << 2)) & 0x3333_3333_3333_3333;
+    value = (value | (value << 1)) & 0x5555_5555_5555_5555;
+    value
+}
+
+// spread the low 21 bits of value out so there are two zeros between each bit
+pub fn spread_bits_3d(value: u32) -> u64 {
+    let mut value = (value & 0x1F_FFFF) as u64;
+    value = (value | (value << 32)) & 0x001F_0000_0000_FFFF;
+    value = (value | (value << 16)) & 0x001F_0000_FF00_00FF;
+    value = (value | (value << 8)) & 0x100F_00F0_0F00_F00F;
+    value = (value | (value << 4)) & 0x10C3_0C30_C30C_30C3;
+    value = (value | (value << 2)) & 0x1249_2492_4924_9249;
+    value
+}
+
+// interleave the bits of the x and y cell numbers
+pub fn encode(x: u32, y: u32) -> u64 {
+    spread_bits(x) | (spread_bits(y) << 1)
+}
+
+// interleave the bits of the x, y and z cell numbers
+pub fn encode_3d(x: u32, y: u32, z: u32) -> u64 {
+    spread_bits_3d(x) | (spread_bits_3d(y) << 1) | (spread_bits_3d(z) << 2)
+}
+
+// key of a position inside the square around center with the given half
+// width. bits is the number of splits the key can describe and must be at
+// most 32 in 2D and 21 in 3D. Positions on the far edge are clamped into the
+// last cell.
+//
+// The key is the same as interleaving the cell numbers, but it is worked out
+// by splitting the square bits times with the same arithmetic the tree uses
+// for its children (Space::child and Space::child_direction). Rounding
+// then can't put a point near a split line on the other side of it from the
+// one the tree would.
+pub fn key<V: Space>(position: &V, center: &V, half_width: &Real, bits: &u32) -> u64 {
+    let mut key = 0;
+    let mut center = *center;
+    let mut half_width = *half_width;
+    for _ in 0..*bits {
+        let digit = (0..V::DIM)
+            .filter(|axis| position.axis(*axis) >= center.axis(*axis))
+            .fold(0, |digit, axis| digit | 1 << axis);
+        key = key << V::DIM | digit as u64;
+        half_width /= 2.0;
+        center += V::child_direction(V::digit_to_child(digit)) * half_width;
+    }
+    key
+}
+
+// the DIM bits of key that say which child the point is in after depth
+// splits. Bit 0 is set for the upper half of x, bit 1 for the upper half of
+// y and bit 2 for the upper half of z
+pub fn digit<V: Space>(key: &u64, depth: &u32, bits: &u32) -> usize {
+    ((key >> (V::DIM as u32 * (bits - depth - 1))) & (V::CHILDREN as u64 - 1)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::space::Vector3;
+    use crate::tree::Vector;
+
+    #[test]
+    fn morton_encode() {
+        assert_eq!(encode(0, 0), 0);
+        assert_eq!(encode(1, 0), 0b01);
+        assert_eq!(encode(0, 1), 0b10);
+        assert_eq!(encode(0b11, 0b01), 0b0111);
+        assert_eq!(encode(u32::MAX, 0), 0x5555_5555_5555_5555);
+        assert_eq!(encode(0, u32::MAX), 0xAAAA_AAAA_AAAA_AAAA);
+    }
+
+    #[test]
+    fn morton_key_quadrants() {
+        let center = Vector { x: 0.0, y: 0.0 };
+        // one split so each key is just the quad
+        let bits = 1;
+        let key_of = |x, y| key(&Vector { x, y }, &center, &10.0, &bits);
+        assert_eq!(key_of(-5.0, -5.0), 0b00);
+        assert_eq!(key_of(5.0, -5.0), 0b01);
+        assert_eq!(key_of(-5.0, 5.0), 0b10);
+        assert_eq!(key_of(5.0, 5.0), 0b11);
+        // the edges of the square are clamped in
+        assert_eq!(key_of(10.0, 10.0), 0b11);
+        assert_eq!(key_of(-10.0, -10.0), 0b00);
+    }
+
+    #[test]
+    fn morton_digit() {
+        let key = encode(0b10, 0b01);
+        // first split is x upper, y lower
+        assert_eq!(digit::<Vector>(&key, &0, &2), 0b01);
+        // second split is x lower, y upper
+        assert_eq!(digit::<Vector>(&key, &1, &2), 0b10);
+    }
+
+    #[test]
+    fn morton_encode_3d() {
+        assert_eq!(encode_3d(1, 0, 0), 0b001);
+        assert_eq!(encode_3d(0, 1, 0), 0b010);
+        assert_eq!(encode_3d(0, 0, 1), 0b100);
+        assert_eq!(encode_3d(0b11, 0b01, 0b10), 0b101_011);
+        assert_eq!(encode_3d(0x1F_FFFF, 0, 0), 0x1249_2492_4924_9249);
+        // the last cell of every axis fills all 63 bits
+        assert_eq!(
+            encode_3d(0x1F_FFFF, 0x1F_FFFF, 0x1F_FFFF),
+            0x7FFF_FFFF_FFFF_FFFF
+        );
+    }
+
+    #[test]
+    fn morton_digit_3d() {
+        let center = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let position = Vector3 {
+            x: 5.0,
+            y: -5.0,
+            z: 2.0,
+        };
+        let key = key(&position, &center, &10.0, &2);
+        // first split is x upper, y lower and z upper
+        assert_eq!(digit::<Vector3>(&key, &0, &2), 0b101);
+        // then x upper, y upper and z lower inside that octant
+        assert_eq!(digit::<Vector3>(&key, &1, &2), 0b011);
+    }
+
+    #[test]
+    fn morton_key_follows_the_children() {
+        // a point on a split line goes to the upper side like Space::child,
+        // and the next number below 0.5 to the lower side, even though it is
+        // lost when measured from the edge of the square
+        let center = Vector { x: 0.0, y: 0.0 };
+        let below = -Real::EPSILON / 4.0;
+        let key_of = |x, y| key(&Vector { x, y }, &center, &1.0, &Vector::MAX_DEPTH);
+        let first = |key: u64| digit::<Vector>(&key, &0, &Vector::MAX_DEPTH);
+        assert_eq!(first(key_of(0.0, 0.0)), 0b11);
+        assert_eq!(first(key_of(below, 0.0)), 0b10);
+        assert_eq!(first(key_of(0.0, below)), 0b01);
+        // the split at a quarter, deeper down
+        let second = |key: u64| digit::<Vector>(&key, &1, &Vector::MAX_DEPTH);
+        assert_eq!(second(key_of(0.5, 0.5)), 0b11);
+        assert_eq!(second(key_of(0.5 + below, 0.5)), 0b10);
+    }
+}
@@ -0,0 +1,123 @@
+// Files written by the headless runner.
+//
+// Snapshots hold one particle per line and the diagnostics one line per
+// snapshot, both as CSV so they load straight into a spreadsheet or numpy.
+// Positions, velocities, masses and times are written back in the units the
+// scenario was given in. Energy stays in simulation units as it is only
+// meant to be compared against the energy at the start.
+use std::io::{self, Write};
+
+use crate::float::Real;
+use crate::simulation::Simulation;
+use crate::tree::Particle;
+use crate::units::UnitSystem;
+
+pub static SNAPSHOT_HEADER: &str = "x,y,vx,vy,mass,radius";
+pub static DIAGNOSTICS_HEADER: &str = "step,time,particles,energy,energy_change,step_size";
+
+// write the particles, given in simulation units, in the units of the scenario
+pub fn write_snapshot<W: Write>(
+    writer: &mut W,
+    list_of_points: &[Particle],
+    units: &UnitSystem,
+) -> io::Result<()> {
+    let mut list_of_points = list_of_points.to_vec();
+    units.from_simulation(&mut list_of_points);
+    writeln!(writer, "{}", SNAPSHOT_HEADER)?;
+    for point in &list_of_points {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            point.position.x,
+            point.position.y,
+            point.velocity.x,
+            point.velocity.y,
+            point.mass,
+            point.radius
+        )?;
+    }
+    Ok(())
+}
+
+// write one line of diagnostics for the state of the simulation after step
+// steps. The energy change is relative to start_energy
+pub fn write_diagnostics<W: Write>(
+    writer: &mut W,
+    step: usize,
+    simulation: &Simulation,
+    units: &UnitSystem,
+    start_energy: &Real,
+) -> io::Result<()> {
+    let energy = simulation.total_energy();
+    writeln!(
+        writer,
+        "{},{},{},{},{},{}",
+        step,
+        units.from_simulation_time(&simulation.time),
+        simulation.particles.len(),
+        energy,
+        (energy - start_energy) / Real::abs(*start_energy),
+        units.from_simulation_time(&simulation.last_step)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::space::Space;
+    use crate::tree::Vector;
+
+    fn pair() -> Vec<Particle> {
+        let point = Particle {
+            position: Vector { x: 10.0, y: 0.0 },
+            velocity: Vector { x: 0.0, y: 2.0 },
+            mass: 1.0,
+            g_vector: Vector::zero(),
+            radius: 0.5,
+            softening: 0.0,
+        };
+        vec![
+            point,
+            Particle {
+                position: -point.position,
+                velocity: -point.velocity,
+                ..point
+            },
+        ]
+    }
+
+    #[test]
+    fn snapshot_has_a_line_per_particle() {
+        let units = UnitSystem::NBody {
+            length: 1.0,
+            mass: 1.0,
+        };
+        let mut written = vec![];
+        write_snapshot(&mut written, &pair(), &units).unwrap();
+        let text = String::from_utf8(written).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [SNAPSHOT_HEADER, "10,0,0,2,1,0.5", "-10,-0,-0,-2,1,0.5"]
+        );
+    }
+
+    #[test]
+    fn diagnostics_match_the_header() {
+        let units = UnitSystem::NBody {
+            length: 1.0,
+            mass: 1.0,
+        };
+        let mut simulation = Simulation::new(pair());
+        let start_energy = simulation.total_energy();
+        simulation.step();
+        let mut written = vec![];
+        write_diagnostics(&mut written, 1, &simulation, &units, &start_energy).unwrap();
+        let text = String::from_utf8(written).unwrap();
+        let values: Vec<&str> = text.trim_end().split(',').collect();
+        assert_eq!(values.len(), DIAGNOSTICS_HEADER.split(',').count());
+        assert_eq!(values[0], "1");
+        assert_eq!(values[2], "2");
+        assert_eq!(values[1].parse::<Real>().unwrap(), simulation.time);
+    }
+}
@@ -0,0 +1,267 @@
+// Demo scenarios.
+//
+// Each scenario builds its particles from a density, in whatever units it is
+// written in, and is converted into simulation units when it is loaded. The
+// window app cycles through them and the headless runner picks one by name.
+use crate::collision;
+use crate::float::{consts::PI, Real, GOLDEN_ANGLE};
+use crate::space::{Space, Vector3};
+use crate::tree::{Particle, Vector};
+use crate::units::UnitSystem;
+
+fn get_number_particles(layer: &i32) -> i32 {
+    if layer == &1 {
+        return 1;
+    }
+    let large_diameter = 60 * layer + 60;
+    (PI / (Real::asin(60.0 / large_diameter as Real))) as i32
+}
+
+fn map_to_position(layer: &i32, delta_theta: &Real, center: &Vector) -> Vector {
+    if *layer == 1 {
+        return *center;
+    }
+
+    let large_radius = 30 * layer + 30;
+    let offset = Vector {
+        x: large_radius as Real,
+        y: 0.0,
+    };
+    *center + offset.rotate(delta_theta)
+}
+
+fn build_mass(
+    num_particles: &i32,
+    center: &Vector,
+    velocity: &Vector,
+    density: &Real,
+) -> Vec<Particle> {
+    let mut layer = 1;
+    let mut num_placed = 0;
+    let mut mass: Vec<Particle> = vec![];
+
+    'outer: loop {
+        let current_layer_num = get_number_particles(&layer);
+        for i in 0..current_layer_num {
+            let delta_theta = (360.0 / current_layer_num as Real) * i as Real;
+            let position = map_to_position(&layer, &delta_theta, center);
+            // let velocity = Vector{
+            //     x: - center.y - position.y,
+            //     y: center.x - position.x
+            // }.normialize().multiple(&10.0);
+            // // add particle to mass
+            // let velocity = Vector {
+            //     x: position.x - ((center.x - position.x) * (1.0 / normal_mag)),
+            //     y: position.y + ((center.y - position.y) * (1.0 / normal_mag)),
+            // };
+
+            mass.push(Particle {
+                position,
+                velocity: *velocity,
+                // heavy enough that the particles visibly pull on each other
+                mass: 10000.0,
+                g_vector: Vector::zero(),
+                radius: collision::radius_for(&10000.0, density),
+                softening: 0.0,
+            });
+            num_placed += 1;
+            // if we are done then exit
+            if num_placed == *num_particles {
+                break 'outer;
+            }
+        }
+        layer += 1;
+    }
+    mass
+}
+
+// build two masses here that have opposite positions and velocities
+// This allows a demo of the two counter rotating
+pub fn build_scenario(density: &Real) -> Vec<Particle> {
+    let mut list_of_points = build_mass(
+        &5,
+        &Vector { x: -160.0, y: 0.0 },
+        &Vector { x: 0.0, y: -10.0 },
+        density,
+    );
+    let mut another_list = build_mass(
+        &5,
+        &Vector { x: 160.0, y: 0.0 },
+        &Vector { x: 0.0, y: 10.0 },
+        density,
+    );
+
+    list_of_points.append(&mut another_list);
+    list_of_points
+}
+
+// a star with a disk of small particles on circular orbits around it. With
+// merging on the debris slowly clumps together into larger bodies
+pub fn build_debris_disk(density: &Real) -> Vec<Particle> {
+    let star_mass = 40000.0;
+    let mut list_of_points = vec![Particle {
+        position: Vector::zero(),
+        velocity: Vector::zero(),
+        mass: star_mass,
+        g_vector: Vector::zero(),
+        radius: collision::radius_for(&star_mass, density),
+        softening: 0.0,
+    }];
+    let count = 300;
+    for i in 0..count {
+        // spread the debris over the disk with the golden angle so it is
+        // even without needing random numbers
+        let angle = i as Real * GOLDEN_ANGLE;
+        let distance = 80.0 + 220.0 * Real::sqrt(i as Real / count as Real);
+        // circular speed around the star, the pull of the disk is ignored
+        let speed = Real::sqrt(star_mass / distance);
+        list_of_points.push(Particle {
+            position: Vector {
+                x: distance,
+                y: 0.0,
+            }
+            .rotate(&angle),
+            velocity: Vector { x: 0.0, y: speed }.rotate(&angle),
+            mass: 100.0,
+            g_vector: Vector::zero(),
+            radius: collision::radius_for(&100.0, density),
+            softening: 0.0,
+        });
+    }
+    list_of_points
+}
+
+// a ball of equal mass stars spread evenly through its volume, for the 3D
+// octree. The points sit on a spiral over nested shells so no random numbers
+// are needed
+pub fn build_cluster_3d() -> Vec<Particle<Vector3>> {
+    let count = 400;
+    (0..count)
+        .map(|i| {
+            let angle = i as Real * GOLDEN_ANGLE;
+            let height = 1.0 - 2.0 * (i as Real + 0.5) / count as Real;
+            let ring = Real::sqrt(1.0 - height * height);
+            let distance = 300.0 * Real::cbrt((i * 37 % count) as Real / count as Real + 0.01);
+            Particle {
+                position: Vector3 {
+                    x: distance * ring * Real::cos(angle),
+                    y: distance * ring * Real::sin(angle),
+                    z: distance * height,
+                },
+                velocity: Vector3::zero(),
+                mass: 100.0,
+                g_vector: Vector3::zero(),
+                radius: 0.0,
+                softening: 0.0,
+            }
+        })
+        .collect()
+}
+
+// the Sun and the four inner planets on circular orbits, given in AU, solar
+// masses and years. The planets are spread around the Sun by the golden angle
+// and have their real radii so they never touch
+pub fn build_inner_planets(_density: &Real) -> Vec<Particle> {
+    let sun = Particle {
+        position: Vector::zero(),
+        velocity: Vector::zero(),
+        mass: 1.0,
+        g_vector: Vector::zero(),
+        radius: 0.00465,
+        softening: 0.0,
+    };
+    // distance, mass and radius of Mercury, Venus, the Earth and Mars
+    let planets: [(Real, Real, Real); 4] = [
+        (0.387, 1.66e-7, 1.63e-5),
+        (0.723, 2.45e-6, 4.05e-5),
+        (1.0, 3.0e-6, 4.26e-5),
+        (1.524, 3.23e-7, 2.27e-5),
+    ];
+    let gravity = UnitSystem::Astronomical.gravitational_constant();
+    let mut list_of_points = vec![sun];
+    for (i, (distance, mass, radius)) in planets.into_iter().enumerate() {
+        let angle = i as Real * GOLDEN_ANGLE;
+        let speed = Real::sqrt(gravity * sun.mass / distance);
+        list_of_points.push(Particle {
+            position: Vector {
+                x: distance,
+                y: 0.0,
+            }
+            .rotate(&angle),
+            velocity: Vector { x: 0.0, y: speed }.rotate(&angle),
+            mass,
+            radius,
+            ..sun
+        });
+    }
+    list_of_points
+}
+
+// mass per unit area of every particle. This gives the particles of the two
+// masses a radius of 30
+pub static DENSITY: Real = 10000.0 / (PI * 30.0 * 30.0);
+
+// builds the particles of a scenario from the density, in the units of the
+// scenario
+pub type Scenario = fn(&Real) -> Vec<Particle>;
+
+// the units the demo scenarios that were written for the simulation are in
+pub static SIMULATION_UNITS: UnitSystem = UnitSystem::NBody {
+    length: 1.0,
+    mass: 1.0,
+};
+
+// the demo scenarios and the units they are given in, press S to restart with
+// the next one
+pub static SCENARIOS: [(&str, UnitSystem, Scenario); 3] = [
+    ("two masses", SIMULATION_UNITS, build_scenario),
+    ("debris disk", SIMULATION_UNITS, build_debris_disk),
+    (
+        "inner planets",
+        UnitSystem::Astronomical,
+        build_inner_planets,
+    ),
+];
+
+// build a scenario and convert it into simulation units
+pub fn load_scenario(units: &UnitSystem, scenario: Scenario, density: &Real) -> Vec<Particle> {
+    let mut list_of_points = scenario(density);
+    units.to_simulation(&mut list_of_points);
+    list_of_points
+}
+
+// look a scenario up by name. Case is ignored and dashes stand for spaces so
+// names can be passed on the command line without quotes
+pub fn find(name: &str) -> Option<(&'static str, UnitSystem, Scenario)> {
+    let name = name.to_lowercase().replace('-', " ");
+    SCENARIOS
+        .iter()
+        .find(|(scenario_name, _, _)| *scenario_name == name)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_by_name() {
+        let (name, units, _) = find("Debris-Disk").unwrap();
+        assert_eq!(name, "debris disk");
+        assert_eq!(units, SIMULATION_UNITS);
+        assert_eq!(find("inner planets").unwrap().1, UnitSystem::Astronomical);
+        assert!(find("no such scenario").is_none());
+    }
+
+    #[test]
+    fn inner_planets_in_simulation_units() {
+        let (_, units, scenario) = find("inner-planets").unwrap();
+        let list_of_points = load_scenario(&units, scenario, &DENSITY);
+        assert_eq!(list_of_points.len(), 5);
+        // with G = 1 and the Sun as the unit of mass the Earth moves at about
+        // 1 AU per time unit
+        let earth = list_of_points[3];
+        assert!(Real::abs(earth.position.length() - 1.0) < 1e-6);
+        assert!(Real::abs(earth.velocity.length() - 1.0) < 1e-3);
+    }
+}
@@ -0,0 +1,527 @@
+// A running simulation.
+//
+// Owns the particles together with everything that moves them on: the
+// gravity solvers, the integrator, how big the steps are, what happens when
+// particles touch and the time that has passed. The window app and other
+// tools only pick the settings and call step or run_until, so the physics can
+// be used without opening a window.
+//
+// A simulation runs in the plane by default and in 3D as Simulation<Vector3>.
+// The fast multipole method is only written for the plane, so each space has
+// its own list of solvers to pick from and Solver3d has no Fmm to pick.
+use std::fmt::Debug;
+
+use crate::block::BlockSteps;
+use crate::collision::{self, Response};
+use crate::float::Real;
+use crate::fmm::Fmm;
+use crate::integrator::{self, Integrator};
+use crate::softening::Softening;
+use crate::solver::{BarnesHut, DirectSum, ForceSolver};
+use crate::space::{Space, Vector3};
+use crate::timestep::{AdaptiveStep, StepSize};
+use crate::tree::{Boundary, Particle, Vector};
+
+// which of the solvers works out gravity in the plane
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Solver {
+    BarnesHut,
+    DirectSum,
+    Fmm,
+}
+
+// which of the solvers works out gravity in 3D
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Solver3d {
+    BarnesHut,
+    DirectSum,
+}
+
+// a space a simulation can run in, with the solvers there are for it
+pub trait SimulationSpace: Space {
+    // the solvers that can be picked, the first is the default
+    type Solver: Copy + PartialEq + Debug + 'static;
+    const SOLVERS: &'static [Self::Solver];
+    // the fast multipole solver, () where there isn't one
+    type Fmm;
+    fn fmm() -> Self::Fmm;
+    fn set_fmm_softening(fmm: &mut Self::Fmm, softening: Softening);
+    // the solver that was picked
+    fn solver<'a>(
+        solver: &Self::Solver,
+        barnes_hut: &'a BarnesHut<Self>,
+        direct_sum: &'a DirectSum<Self>,
+        fmm: &'a Self::Fmm,
+    ) -> &'a dyn ForceSolver<Self>;
+    fn solver_mut<'a>(
+        solver: &Self::Solver,
+        barnes_hut: &'a mut BarnesHut<Self>,
+        direct_sum: &'a mut DirectSum<Self>,
+        fmm: &'a mut Self::Fmm,
+    ) -> &'a mut dyn ForceSolver<Self>;
+}
+
+impl SimulationSpace for Vector {
+    type Solver = Solver;
+    const SOLVERS: &'static [Solver] = &[Solver::BarnesHut, Solver::DirectSum, Solver::Fmm];
+    type Fmm = Fmm;
+
+    fn fmm() -> Fmm {
+        Fmm::new(0.5, 6, Boundary::Fit, true)
+    }
+
+    fn set_fmm_softening(fmm: &mut Fmm, softening: Softening) {
+        fmm.set_softening(softening);
+    }
+
+    fn solver<'a>(
+        solver: &Solver,
+        barnes_hut: &'a BarnesHut,
+        direct_sum: &'a DirectSum,
+        fmm: &'a Fmm,
+    ) -> &'a dyn ForceSolver {
+        match solver {
+            Solver::BarnesHut => barnes_hut,
+            Solver::DirectSum => direct_sum,
+            Solver::Fmm => fmm,
+        }
+    }
+
+    fn solver_mut<'a>(
+        solver: &Solver,
+        barnes_hut: &'a mut BarnesHut,
+        direct_sum: &'a mut DirectSum,
+        fmm: &'a mut Fmm,
+    ) -> &'a mut dyn ForceSolver {
+        match solver {
+            Solver::BarnesHut => barnes_hut,
+            Solver::DirectSum => direct_sum,
+            Solver::Fmm => fmm,
+        }
+    }
+}
+
+impl SimulationSpace for Vector3 {
+    type Solver = Solver3d;
+    const SOLVERS: &'static [Solver3d] = &[Solver3d::BarnesHut, Solver3d::DirectSum];
+    type Fmm = ();
+
+    fn fmm() {}
+
+    fn set_fmm_softening(_fmm: &mut (), _softening: Softening) {}
+
+    fn solver<'a>(
+        solver: &Solver3d,
+        barnes_hut: &'a BarnesHut<Vector3>,
+        direct_sum: &'a DirectSum<Vector3>,
+        _fmm: &'a (),
+    ) -> &'a dyn ForceSolver<Vector3> {
+        match solver {
+            Solver3d::BarnesHut => barnes_hut,
+            Solver3d::DirectSum => direct_sum,
+        }
+    }
+
+    fn solver_mut<'a>(
+        solver: &Solver3d,
+        barnes_hut: &'a mut BarnesHut<Vector3>,
+        direct_sum: &'a mut DirectSum<Vector3>,
+        _fmm: &'a mut (),
+    ) -> &'a mut dyn ForceSolver<Vector3> {
+        match solver {
+            Solver3d::BarnesHut => barnes_hut,
+            Solver3d::DirectSum => direct_sum,
+        }
+    }
+}
+
+pub struct Simulation<V: SimulationSpace = Vector> {
+    pub particles: Vec<Particle<V>>,
+    pub solver: V::Solver,
+    pub barnes_hut: BarnesHut<V>,
+    pub direct_sum: DirectSum<V>,
+    pub fmm: V::Fmm,
+    pub integrator: Box<dyn Integrator<V>>,
+    pub step_size: StepSize,
+    // when on every particle moves on its own power of two step and the
+    // integrator and step size above are ignored
+    pub block_steps: bool,
+    pub block: BlockSteps<V>,
+    pub response: Response,
+    // simulated time since the start
+    pub time: Real,
+    // size of the last step taken
+    pub last_step: Real,
+    softening: Softening,
+    collision_pairs: Vec<(usize, usize)>,
+}
+
+impl<V: SimulationSpace> Simulation<V> {
+    // the settings the window app starts with. The Barnes-Hut tree has an
+    // opening angle of 0.5, sizes its root from the particles and is built
+    // from Morton sorted particles. Steps are sized from the largest
+    // acceleration, measured against the size of the particles, see
+    // scale_steps. Particles that touch bounce apart keeping half of their
+    // closing speed
+    pub fn new(particles: Vec<Particle<V>>) -> Simulation<V> {
+        let mut simulation = Simulation {
+            particles,
+            solver: V::SOLVERS[0],
+            barnes_hut: BarnesHut::new(0.5, Boundary::Fit, true),
+            direct_sum: DirectSum::new(Softening::None),
+            fmm: V::fmm(),
+            integrator: integrator::all().remove(0),
+            step_size: StepSize::Adaptive(AdaptiveStep {
+                eta: 0.2,
+                softening: 10.0,
+                min_step: 0.0005,
+                max_step: 0.05,
+            }),
+            block_steps: false,
+            block: BlockSteps::new(0.05, 6, 0.2, 10.0),
+            response: Response::Bounce(0.5),
+            time: 0.0,
+            last_step: 0.0,
+            softening: Softening::None,
+            collision_pairs: vec![],
+        };
+        simulation.scale_steps();
+        simulation
+    }
+
+    // start again from new particles, keeping the settings
+    pub fn reset(&mut self, particles: Vec<Particle<V>>) {
+        self.particles = particles;
+        self.time = 0.0;
+        self.last_step = 0.0;
+        self.block.reset();
+        self.integrator.reset();
+    }
+
+    // size the adaptive and block steps to the particles so scenarios in
+    // real units step as finely as the N-body demos, see AdaptiveStep::fit.
+    // Switches to adaptive steps. Nothing changes if the particles have no
+    // size or mass to measure
+    pub fn scale_steps(&mut self) {
+        if let Some(adaptive) = AdaptiveStep::fit(&self.particles) {
+            self.step_size = StepSize::Adaptive(adaptive);
+            self.block.max_step = adaptive.max_step;
+            self.block.eta = adaptive.eta;
+            self.block.softening = adaptive.softening;
+            self.block.reset();
+        }
+    }
+
+    pub fn solver_name(&self) -> &'static str {
+        V::solver(&self.solver, &self.barnes_hut, &self.direct_sum, &self.fmm).name()
+    }
+
+    pub fn softening(&self) -> Softening {
+        self.softening
+    }
+
+    // soften gravity the same way in every solver
+    pub fn set_softening(&mut self, softening: Softening) {
+        self.softening = softening;
+        self.barnes_hut.set_softening(softening);
+        self.direct_sum.set_softening(softening);
+        V::set_fmm_softening(&mut self.fmm, softening);
+        self.integrator.reset();
+    }
+
+    pub fn total_energy(&self) -> Real {
+        integrator::total_energy(&self.particles, &1.0)
+    }
+
+    // size the next step would be
+    pub fn next_step_size(&self) -> Real {
+        if self.block_steps {
+            self.block.max_step
+        } else {
+            self.step_size.step_size(&self.particles)
+        }
+    }
+
+    // take one step of the size from next_step_size
+    pub fn step(&mut self) -> Real {
+        let delta_time = self.next_step_size();
+        self.step_by(&delta_time);
+        delta_time
+    }
+
+    // take one step of the given size. With block steps on the size is always
+    // the block step
+    pub fn step_by(&mut self, delta_time: &Real) {
+        let solver = V::solver_mut(
+            &self.solver,
+            &mut self.barnes_hut,
+            &mut self.direct_sum,
+            &mut self.fmm,
+        );
+        let delta_time = if self.block_steps {
+            // every particle pulls at each sub step but gravity is only
+            // worked out for the particles whose step ends there
+            let mut forces = |list_of_points: &mut [Particle<V>], active: &[usize]| {
+                solver.calc_gravity_active(list_of_points, active)
+            };
+            self.block.step(&mut self.particles, &mut forces);
+            // the accelerations left behind are from different times
+            self.integrator.reset();
+            self.block.max_step
+        } else {
+            // the integrator asks for the gravity at whatever positions it
+            // needs so the solver works it out every time it calls back
+            let mut forces =
+                |list_of_points: &mut [Particle<V>]| solver.calc_gravity(list_of_points);
+            self.integrator
+                .step(&mut self.particles, delta_time, &mut forces);
+            *delta_time
+        };
+        self.handle_collisions();
+        self.time += delta_time;
+        self.last_step = delta_time;
+    }
+
+    // take one step toward end, shortened so it lands on end if it would go
+    // past. Block steps can only take whole steps so they may go past.
+    // Returns the size of the step
+    pub fn step_toward(&mut self, end: &Real) -> Real {
+        let delta_time = if self.block_steps {
+            self.block.max_step
+        } else {
+            Real::min(self.next_step_size(), end - self.time)
+        };
+        self.step_by(&delta_time);
+        delta_time
+    }
+
+    // step until the time reaches end, see step_toward. Returns the number
+    // of steps taken
+    pub fn run_until(&mut self, end: &Real) -> usize {
+        let mut steps = 0;
+        while self.time < *end {
+            self.step_toward(end);
+            steps += 1;
+        }
+        steps
+    }
+
+    // find the particles that overlap and either bounce them apart or merge
+    // them. Merged particles are removed from the list. The pairs are found
+    // with the tree the solver built on its last force pass if that was at
+    // the current positions, which it is after the leapfrog and velocity
+    // Verlet. Otherwise the tree of the Barnes-Hut solver is built for it,
+    // unless no particle has a radius to touch with. Returns the number of
+    // bounces or merges
+    fn handle_collisions(&mut self) -> usize {
+        if self.particles.iter().all(|point| point.radius <= 0.0) {
+            return 0;
+        }
+        let reuse = V::solver(&self.solver, &self.barnes_hut, &self.direct_sum, &self.fmm)
+            .last_tree()
+            .is_some_and(|tree| tree.holds(&self.particles));
+        if !reuse {
+            self.barnes_hut.build(&mut self.particles);
+        }
+        let tree = if reuse {
+            V::solver(&self.solver, &self.barnes_hut, &self.direct_sum, &self.fmm)
+                .last_tree()
+                .unwrap()
+        } else {
+            self.barnes_hut.tree()
+        };
+        tree.collision_pairs(&mut self.collision_pairs);
+        // the same pairs in the same order whichever tree found them
+        self.collision_pairs.sort_unstable();
+        if !self.collision_pairs.is_empty() {
+            // bounces move the particles apart and merges remove them
+            self.integrator.reset();
+        }
+        match self.response {
+            Response::Bounce(restitution) => {
+                collision::resolve(&mut self.particles, &self.collision_pairs, &restitution)
+            }
+            Response::Merge(density) => {
+                collision::merge(&mut self.particles, &self.collision_pairs, &density)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two equal masses on a circular orbit around their middle, far enough
+    // apart that they never touch
+    fn binary() -> Vec<Particle> {
+        let speed = Real::sqrt(100.0 / (4.0 * 50.0));
+        [1.0, -1.0]
+            .iter()
+            .map(|side| Particle {
+                position: Vector {
+                    x: 50.0 * side,
+                    y: 0.0,
+                },
+                velocity: Vector {
+                    x: 0.0,
+                    y: speed * side,
+                },
+                mass: 100.0,
+                g_vector: Vector::zero(),
+                radius: 1.0,
+                softening: 0.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn run_until_lands_on_the_end() {
+        let mut simulation = Simulation::new(binary());
+        let steps = simulation.run_until(&1.0);
+        assert_eq!(simulation.time, 1.0);
+        assert!(
+            steps as Real >= 1.0 / simulation.block.max_step,
+            "{}",
+            steps
+        );
+        simulation.run_until(&1.0);
+        assert_eq!(simulation.time, 1.0);
+    }
+
+    #[test]
+    fn every_solver_keeps_the_orbit() {
+        for solver in Vector::SOLVERS {
+            let mut simulation = Simulation::new(binary());
+            simulation.solver = *solver;
+            simulation.integrator = Box::new(integrator::Leapfrog::default());
+            let start_energy = simulation.total_energy();
+            simulation.run_until(&20.0);
+            let change = (simulation.total_energy() - start_energy) / Real::abs(start_energy);
+            assert!(
+                Real::abs(change) < 1e-3,
+                "{}: {}",
+                simulation.solver_name(),
+                change
+            );
+            assert_eq!(simulation.particles.len(), 2);
+        }
+    }
+
+    #[test]
+    fn every_solver_keeps_the_orbit_in_3d() {
+        // the same binary tipped out of the plane
+        let (sin, cos) = Real::sin_cos(0.6);
+        let tipped = |vector: Vector| Vector3 {
+            x: vector.x,
+            y: vector.y * cos,
+            z: vector.y * sin,
+        };
+        for solver in Vector3::SOLVERS {
+            let mut simulation = Simulation::new(
+                binary()
+                    .iter()
+                    .map(|point| Particle {
+                        position: tipped(point.position),
+                        velocity: tipped(point.velocity),
+                        g_vector: Vector3::zero(),
+                        radius: point.radius,
+                        softening: point.softening,
+                        mass: point.mass,
+                    })
+                    .collect(),
+            );
+            simulation.solver = *solver;
+            simulation.integrator = Box::new(integrator::Leapfrog::default());
+            let start_energy = simulation.total_energy();
+            simulation.run_until(&20.0);
+            let change = (simulation.total_energy() - start_energy) / Real::abs(start_energy);
+            assert!(
+                Real::abs(change) < 1e-3,
+                "{}: {}",
+                simulation.solver_name(),
+                change
+            );
+            // the orbit stays in its tipped plane
+            let normal = Vector3 {
+                x: 0.0,
+                y: -sin,
+                z: cos,
+            };
+            let height = simulation.particles[0].position.dot(&normal);
+            assert!(Real::abs(height) < 1e-3, "{}", height);
+        }
+    }
+
+    #[test]
+    fn block_steps_take_whole_steps() {
+        let mut simulation = Simulation::new(binary());
+        simulation.block_steps = true;
+        simulation.step();
+        assert_eq!(simulation.time, simulation.block.max_step);
+        simulation.reset(binary());
+        assert_eq!(simulation.time, 0.0);
+    }
+
+    #[test]
+    fn steps_follow_the_scenario() {
+        // the inner planets in AU would take steps longer than Mercury's
+        // orbit with the N-body demo settings
+        let (_, units, scenario) = crate::scenario::find("inner planets").unwrap();
+        let list_of_points = crate::scenario::load_scenario(&units, scenario, &1.0);
+        let mercury_year = units.to_simulation_time(&0.241);
+        let mut simulation = Simulation::new(list_of_points);
+        let StepSize::Adaptive(adaptive) = simulation.step_size else {
+            panic!("steps should be adaptive");
+        };
+        assert!(
+            adaptive.max_step < mercury_year / 100.0,
+            "{}",
+            adaptive.max_step
+        );
+        assert!(adaptive.softening < 0.387, "{}", adaptive.softening);
+        assert_eq!(simulation.block.max_step, adaptive.max_step);
+        // and keep Mercury on its orbit for a year
+        simulation.integrator = Box::new(integrator::Leapfrog::default());
+        simulation.run_until(&units.to_simulation_time(&1.0));
+        let distance = simulation.particles[1].position.length();
+        assert!(Real::abs(distance - 0.387) < 0.01, "{}", distance);
+    }
+
+    #[test]
+    fn collisions_reuse_the_solver_tree() {
+        let built = |simulation: &Simulation| simulation.barnes_hut.tree().particle_count();
+        // the fast multipole tree is still where the particles are after a
+        // leapfrog step
+        let mut simulation = Simulation::new(binary());
+        simulation.solver = Solver::Fmm;
+        simulation.integrator = Box::new(integrator::Leapfrog::default());
+        simulation.step();
+        assert_eq!(built(&simulation), 0);
+        // the direct sum has no tree so one is built
+        simulation.solver = Solver::DirectSum;
+        simulation.step();
+        assert_eq!(built(&simulation), 2);
+        // unless nothing has a radius
+        let mut particles = binary();
+        for point in &mut particles {
+            point.radius = 0.0;
+        }
+        let mut simulation = Simulation::new(particles);
+        simulation.solver = Solver::DirectSum;
+        simulation.step();
+        assert_eq!(built(&simulation), 0);
+    }
+
+    #[test]
+    fn merging_removes_particles() {
+        let mut particles = binary();
+        particles[0].position.x = 0.5;
+        particles[1].position.x = -0.5;
+        let mut simulation = Simulation::new(particles);
+        simulation.response = Response::Merge(1.0);
+        simulation.step();
+        assert_eq!(simulation.particles.len(), 1);
+        assert_eq!(simulation.particles[0].mass, 200.0);
+    }
+}
@@ -0,0 +1,156 @@
+// Gravitational softening.
+//
+// Plain gravity goes as 1 / r^2 which blows up when two particles pass close
+// to each other. Softening replaces the point mass with a smoothed one so the
+// pull flattens out inside a softening length. The same kernel is used for
+// particle to particle and particle to tree node interactions.
+//
+// The spline is the cubic spline kernel of Monaghan and Lattanzio. h is the
+// distance where it joins back up with plain gravity, so unlike Plummer it is
+// exactly Newtonian for r >= h. With adaptive softening every particle
+// carries its own length in Particle::softening, set from the distance to
+// its nearest neighbours by Tree::adapt_softening. A pair uses the larger of
+// the two lengths so the force is the same both ways.
+use crate::float::Real;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Softening {
+    // plain 1 / r^2
+    None,
+    // G m / (r^2 + eps^2) with the softening length eps
+    Plummer(Real),
+    // spline kernel with a fixed softening length h
+    Spline(Real),
+    // spline kernel with per particle lengths. Each length is scale times the
+    // distance to the particle's nth nearest neighbour, n being neighbours
+    Adaptive { neighbours: usize, scale: Real },
+}
+
+impl Softening {
+    // magnitude of the acceleration per unit of G * mass at distance. first
+    // and second are the per particle softening lengths of the pair which are
+    // only used by Adaptive
+    pub fn acceleration(&self, distance: &Real, first: &Real, second: &Real) -> Real {
+        match self {
+            Softening::None => 1.0 / (distance * distance),
+            Softening::Plummer(length) => {
+                let soft = distance * distance + length * length;
+                distance / (soft * Real::sqrt(soft))
+            }
+            Softening::Spline(length) => spline(distance, length),
+            Softening::Adaptive { .. } => spline(distance, &Real::max(*first, *second)),
+        }
+    }
+
+    // distance inside which the kernel is clearly not plain gravity. The
+    // spline is exact outside of it, Plummer only gets close
+    pub fn length(&self, first: &Real, second: &Real) -> Real {
+        match self {
+            Softening::None => 0.0,
+            Softening::Plummer(length) | Softening::Spline(length) => *length,
+            Softening::Adaptive { .. } => Real::max(*first, *second),
+        }
+    }
+}
+
+// cubic spline force kernel with support h
+fn spline(distance: &Real, h: &Real) -> Real {
+    if *distance >= *h {
+        return 1.0 / (distance * distance);
+    }
+    let u = distance / h;
+    let scale = distance / (h * h * h);
+    if u < 0.5 {
+        scale * (32.0 / 3.0 + u * u * (32.0 * u - 38.4))
+    } else {
+        scale
+            * (64.0 / 3.0 - 48.0 * u + 38.4 * u * u
+                - 32.0 / 3.0 * u * u * u
+                - 1.0 / (15.0 * u * u * u))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Real, b: Real) -> bool {
+        Real::abs(a - b) <= 1e-4 * Real::abs(b)
+    }
+
+    #[test]
+    fn softening_matches_newton_far_away() {
+        let newton = Softening::None.acceleration(&50.0, &0.0, &0.0);
+        assert_eq!(newton, 1.0 / 2500.0);
+        // the spline is exact outside of h
+        assert_eq!(
+            Softening::Spline(10.0).acceleration(&50.0, &0.0, &0.0),
+            newton
+        );
+        // Plummer is only close
+        assert!(close(
+            Softening::Plummer(0.1).acceleration(&50.0, &0.0, &0.0),
+            newton
+        ));
+    }
+
+    #[test]
+    fn softening_stays_finite_up_close() {
+        for softening in [
+            Softening::Plummer(1.0),
+            Softening::Spline(1.0),
+            Softening::Adaptive {
+                neighbours: 4,
+                scale: 1.0,
+            },
+        ] {
+            let mut last = 0.0;
+            let mut largest: Real = 0.0;
+            for i in 0..=100 {
+                let distance = i as Real / 50.0;
+                let acceleration = softening.acceleration(&distance, &1.0, &0.5);
+                assert!(acceleration.is_finite());
+                largest = Real::max(largest, acceleration);
+                last = acceleration;
+            }
+            // the force goes to zero at the center and never gets much above
+            // 1 / length^2
+            assert_eq!(softening.acceleration(&0.0, &1.0, &0.5), 0.0);
+            assert!(largest < 4.0, "{:?} {}", softening, largest);
+            assert!(last > 0.0);
+        }
+    }
+
+    #[test]
+    fn spline_is_continuous() {
+        let h = 2.0;
+        for edge in [0.5 * h, h] {
+            let below = spline(&(edge - 1e-4), &h);
+            let above = spline(&(edge + 1e-4), &h);
+            assert!(
+                Real::abs(below - above) < 1e-3 * above,
+                "{} {} {}",
+                edge,
+                below,
+                above
+            );
+        }
+    }
+
+    #[test]
+    fn adaptive_uses_the_larger_length() {
+        let adaptive = Softening::Adaptive {
+            neighbours: 4,
+            scale: 1.0,
+        };
+        let spline = Softening::Spline(3.0);
+        assert_eq!(
+            adaptive.acceleration(&1.0, &3.0, &0.5),
+            spline.acceleration(&1.0, &0.0, &0.0)
+        );
+        assert_eq!(
+            adaptive.acceleration(&1.0, &0.5, &3.0),
+            spline.acceleration(&1.0, &0.0, &0.0)
+        );
+    }
+}
@@ -0,0 +1,372 @@
+// Gravity solvers.
+//
+// A force solver fills in the acceleration of gravity of every particle. The
+// Barnes-Hut tree is the fast approximate one and direct summation works out
+// every pair exactly. Both use the same Particle and softening so the direct
+// sum can be used as the reference when measuring the error of the tree, and
+// small systems can just be run exactly. Both work in 2D and 3D, the fast
+// multipole solver in the fmm module is 2D only.
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::float::Real;
+use crate::softening::Softening;
+use crate::space::Space;
+use crate::tree::{Boundary, Particle, Tree, Vector};
+
+pub trait ForceSolver<V: Space = Vector> {
+    fn name(&self) -> &'static str;
+    // add the acceleration of gravity on every particle to its g_vector
+    fn calc_gravity(&mut self, list_of_points: &mut [Particle<V>]);
+    // same as calc_gravity but only the particles at the indices in active
+    // are updated. Every particle still pulls on them
+    fn calc_gravity_active(&mut self, list_of_points: &mut [Particle<V>], active: &[usize]);
+    fn set_softening(&mut self, softening: Softening);
+
+    // the tree the last call built, for solvers that build one
+    fn last_tree(&self) -> Option<&Tree<V>> {
+        None
+    }
+}
+
+// Rebuilds a tree from the particles on every call and keeps it between
+// calls so its memory is reused. Shared by the tree based solvers
+#[derive(Debug)]
+pub struct TreeBuilder<V = Vector> {
+    pub boundary: Boundary,
+    // build the tree from Morton sorted particles instead of one at a time
+    pub sorted_build: bool,
+    tree: Tree<V>,
+    out_of_bounds: usize,
+}
+
+impl<V: Space> TreeBuilder<V> {
+    pub fn new(boundary: Boundary, sorted_build: bool) -> TreeBuilder<V> {
+        TreeBuilder {
+            boundary,
+            sorted_build,
+            tree: Tree::new(),
+            out_of_bounds: 0,
+        }
+    }
+
+    // the tree from the last build
+    pub fn tree(&self) -> &Tree<V> {
+        &self.tree
+    }
+
+    // number of particles left out of the last build because they are outside
+    // of a fixed boundary
+    pub fn out_of_bounds(&self) -> usize {
+        self.out_of_bounds
+    }
+
+    // rebuild the tree from the particles. Adaptive softening lengths are
+    // written back to list_of_points
+    pub fn build(&mut self, list_of_points: &mut [Particle<V>]) -> &Tree<V> {
+        self.out_of_bounds = self
+            .tree
+            .rebuild(&self.boundary, list_of_points, &self.sorted_build);
+        &self.tree
+    }
+
+    pub fn set_softening(&mut self, softening: Softening) {
+        self.tree.set_softening(softening);
+    }
+}
+
+// O(nlog(n)) tree solver
+#[derive(Debug)]
+pub struct BarnesHut<V = Vector> {
+    // opening angle. Lower is more accurate and slower
+    pub theta: Real,
+    pub builder: TreeBuilder<V>,
+}
+
+impl<V: Space> BarnesHut<V> {
+    pub fn new(theta: Real, boundary: Boundary, sorted_build: bool) -> BarnesHut<V> {
+        BarnesHut {
+            theta,
+            builder: TreeBuilder::new(boundary, sorted_build),
+        }
+    }
+
+    pub fn tree(&self) -> &Tree<V> {
+        self.builder.tree()
+    }
+
+    pub fn out_of_bounds(&self) -> usize {
+        self.builder.out_of_bounds()
+    }
+
+    pub fn build(&mut self, list_of_points: &mut [Particle<V>]) {
+        self.builder.build(list_of_points);
+    }
+}
+
+impl<V: Space> ForceSolver<V> for BarnesHut<V> {
+    fn name(&self) -> &'static str {
+        "Barnes-Hut"
+    }
+
+    fn calc_gravity(&mut self, list_of_points: &mut [Particle<V>]) {
+        let tree = self.builder.build(list_of_points);
+        // big scary O(nlog(n)) apply forces calc time.
+        // Traverse the tree and accumulate force vectors of gravity
+        #[cfg(feature = "parallel")]
+        tree.calc_gravity_vector_parallel(list_of_points, &self.theta);
+        #[cfg(not(feature = "parallel"))]
+        tree.calc_gravity_vector(list_of_points, &self.theta);
+    }
+
+    fn calc_gravity_active(&mut self, list_of_points: &mut [Particle<V>], active: &[usize]) {
+        let tree = self.builder.build(list_of_points);
+        tree.calc_gravity_vector_active(list_of_points, active, &self.theta);
+    }
+
+    fn set_softening(&mut self, softening: Softening) {
+        self.builder.set_softening(softening);
+    }
+
+    fn last_tree(&self) -> Option<&Tree<V>> {
+        Some(self.builder.tree())
+    }
+}
+
+// exact O(n^2) solver that works out the pull of every pair
+#[derive(Debug, Clone)]
+pub struct DirectSum<V = Vector> {
+    pub softening: Softening,
+    // copy of the particles doing the pulling, kept so it doesn't allocate
+    // every call
+    sources: Vec<Particle<V>>,
+}
+
+impl<V: Space> DirectSum<V> {
+    pub fn new(softening: Softening) -> DirectSum<V> {
+        DirectSum {
+            softening,
+            sources: vec![],
+        }
+    }
+
+    // copy the particles and set the adaptive softening lengths the same way
+    // Tree::adapt_softening does, only by checking every pair
+    fn prepare(&mut self, list_of_points: &mut [Particle<V>]) {
+        if let Softening::Adaptive { neighbours, scale } = self.softening {
+            debug_assert!(neighbours > 0);
+            let mut distances = vec![];
+            for i in 0..list_of_points.len() {
+                distances.clear();
+                for (j, other) in list_of_points.iter().enumerate() {
+                    if i != j {
+                        distances.push(list_of_points[i].position.get_distance(&other.position));
+                    }
+                }
+                distances.sort_by(Real::total_cmp);
+                list_of_points[i].softening = distances
+                    .get(neighbours - 1)
+                    .or(distances.last())
+                    .map_or(0.0, |near| near * scale);
+            }
+        }
+        self.sources.clear();
+        self.sources.extend_from_slice(list_of_points);
+    }
+}
+
+// pull of every source on one particle. Particles sitting on the same spot
+// don't pull on each other, which also skips the particle itself
+fn direct_gravity<V: Space>(
+    point: &mut Particle<V>,
+    sources: &[Particle<V>],
+    softening: &Softening,
+) {
+    for other in sources {
+        if point.position != other.position {
+            point.apply_softened_force(other, softening);
+        }
+    }
+}
+
+impl<V: Space> ForceSolver<V> for DirectSum<V> {
+    fn name(&self) -> &'static str {
+        "direct sum"
+    }
+
+    fn calc_gravity(&mut self, list_of_points: &mut [Particle<V>]) {
+        self.prepare(list_of_points);
+        let sources = &self.sources;
+        let softening = &self.softening;
+        #[cfg(feature = "parallel")]
+        list_of_points
+            .par_iter_mut()
+            .for_each(|point| direct_gravity(point, sources, softening));
+        #[cfg(not(feature = "parallel"))]
+        for point in list_of_points {
+            direct_gravity(point, sources, softening);
+        }
+    }
+
+    fn calc_gravity_active(&mut self, list_of_points: &mut [Particle<V>], active: &[usize]) {
+        self.prepare(list_of_points);
+        for index in active {
+            direct_gravity(&mut list_of_points[*index], &self.sources, &self.softening);
+        }
+    }
+
+    fn set_softening(&mut self, softening: Softening) {
+        self.softening = softening;
+    }
+}
+
+// relative error of the acceleration of every particle in approximate
+// against the same particle in reference, |a - a_ref| / |a_ref|
+pub fn relative_errors<V: Space>(
+    reference: &[Particle<V>],
+    approximate: &[Particle<V>],
+) -> Vec<Real> {
+    reference
+        .iter()
+        .zip(approximate)
+        .map(|(exact, approx)| {
+            exact.g_vector.get_distance(&approx.g_vector) / exact.g_vector.length()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::space::Vector3;
+
+    // a small spiral of particles with different masses
+    fn spiral() -> Vec<Particle> {
+        fixtures::spiral(80, |i| 10.0 + i as Real * 4.7, |i| 1.0 + (i % 3) as Real)
+    }
+
+    #[test]
+    fn direct_sum_two_bodies() {
+        let mut list_of_points = spiral()[..2].to_vec();
+        list_of_points[0].position = Vector { x: 0.0, y: 0.0 };
+        list_of_points[1].position = Vector { x: 10.0, y: 0.0 };
+        list_of_points[1].mass = 200.0;
+        DirectSum::new(Softening::None).calc_gravity(&mut list_of_points);
+        // G * m / r^2 towards the other particle
+        assert_eq!(list_of_points[0].g_vector.x, 2.0);
+        assert_eq!(list_of_points[0].g_vector.y, 0.0);
+        assert_eq!(
+            list_of_points[1].g_vector.x,
+            -list_of_points[0].mass / 100.0
+        );
+    }
+
+    #[test]
+    fn tree_with_zero_theta_matches_direct_sum() {
+        for softening in [
+            Softening::None,
+            Softening::Spline(30.0),
+            Softening::Adaptive {
+                neighbours: 3,
+                scale: 1.5,
+            },
+        ] {
+            let mut exact = spiral();
+            let mut direct = DirectSum::new(softening);
+            direct.calc_gravity(&mut exact);
+
+            let mut approx = spiral();
+            let mut barnes_hut = BarnesHut::new(0.0, Boundary::Fit, true);
+            barnes_hut.set_softening(softening);
+            barnes_hut.calc_gravity(&mut approx);
+
+            for error in relative_errors(&exact, &approx) {
+                assert!(error < 1e-4, "{:?} {}", softening, error);
+            }
+        }
+    }
+
+    #[test]
+    fn barnes_hut_error_is_small() {
+        let mut exact = spiral();
+        DirectSum::new(Softening::None).calc_gravity(&mut exact);
+        let mut approx = spiral();
+        BarnesHut::new(0.5, Boundary::Fit, true).calc_gravity(&mut approx);
+
+        let mut errors = relative_errors(&exact, &approx);
+        errors.sort_by(Real::total_cmp);
+        // the tree isn't exact but most particles should be close
+        assert!(errors[errors.len() / 2] < 0.01, "{:?}", errors);
+        assert!(errors[errors.len() / 2] > 0.0);
+    }
+
+    #[test]
+    fn active_matches_full() {
+        let active = [0, 5, 41, 79];
+        let solvers: [Box<dyn ForceSolver>; 2] = [
+            Box::new(DirectSum::new(Softening::Plummer(5.0))),
+            Box::new(BarnesHut::new(0.5, Boundary::Fit, true)),
+        ];
+        for mut solver in solvers {
+            let mut all = spiral();
+            solver.calc_gravity(&mut all);
+            let mut some = spiral();
+            solver.calc_gravity_active(&mut some, &active);
+            for (i, (a, b)) in all.iter().zip(&some).enumerate() {
+                if active.contains(&i) {
+                    assert!(
+                        Real::abs(a.g_vector.x - b.g_vector.x) < 1e-6,
+                        "{}",
+                        solver.name()
+                    );
+                    assert!(
+                        Real::abs(a.g_vector.y - b.g_vector.y) < 1e-6,
+                        "{}",
+                        solver.name()
+                    );
+                } else {
+                    assert_eq!(b.g_vector.x, 0.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn barnes_hut_in_3d() {
+        // the spiral wound up into a helix
+        let helix: Vec<Particle<Vector3>> = spiral()
+            .iter()
+            .enumerate()
+            .map(|(i, point)| Particle {
+                position: Vector3 {
+                    x: point.position.x,
+                    y: point.position.y,
+                    z: i as Real * 3.3 - 130.0,
+                },
+                velocity: Vector3::zero(),
+                g_vector: Vector3::zero(),
+                radius: 0.0,
+                softening: 0.0,
+                mass: point.mass,
+            })
+            .collect();
+        let mut exact = helix.clone();
+        DirectSum::new(Softening::Plummer(2.0)).calc_gravity(&mut exact);
+
+        let mut opened = helix.clone();
+        let mut barnes_hut = BarnesHut::new(0.0, Boundary::Fit, true);
+        barnes_hut.set_softening(Softening::Plummer(2.0));
+        barnes_hut.calc_gravity(&mut opened);
+        for error in relative_errors(&exact, &opened) {
+            assert!(error < 1e-4, "{}", error);
+        }
+
+        let mut approx = helix.clone();
+        barnes_hut.theta = 0.5;
+        barnes_hut.calc_gravity(&mut approx);
+        let mut errors = relative_errors(&exact, &approx);
+        errors.sort_by(Real::total_cmp);
+        assert!(errors[errors.len() / 2] < 0.01, "{:?}", errors);
+    }
+}
@@ -0,0 +1,433 @@
+// Positions and directions in two or three dimensions.
+//
+// The tree, the solvers, the integrators and the collision code only add,
+// scale and measure vectors, so they are written against the Space trait and
+// run the same in 2D and 3D. tree::Vector is the plane split into a quad tree
+// and Vector3 is full 3D split into an octree. The only parts that depend on
+// the dimension are how many axes there are and how a node is split into
+// children.
+use std::fmt::Debug;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub};
+
+use crate::float::Real;
+use crate::tree::Vector;
+
+pub trait Space:
+    Debug
+    + Copy
+    + PartialEq
+    + Send
+    + Sync
+    + 'static
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Real, Output = Self>
+    + Div<Real, Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+{
+    // number of axes
+    const DIM: usize;
+    // number of children a node is split into, 2^DIM
+    const CHILDREN: usize;
+    // deepest a node can be split. Morton keys hold DIM bits per split in a
+    // u64 so 3D can't go as deep as 2D
+    const MAX_DEPTH: u32;
+
+    fn zero() -> Self;
+
+    // value along an axis, 0 is x, 1 is y and 2 is z
+    fn axis(&self, axis: usize) -> Real;
+    fn axis_mut(&mut self, axis: usize) -> &mut Real;
+
+    // index of the child of a node around center that the position falls in.
+    // Positions on a split line go to the positive side
+    fn child(&self, center: &Self) -> usize;
+
+    // direction from the center of a node to the center of a child, every
+    // axis is either 1 or -1
+    fn child_direction(child: usize) -> Self;
+
+    // child that holds the cells with the given Morton digit, see morton::digit
+    fn digit_to_child(digit: usize) -> usize;
+
+    // return a vector with the applied scale factor
+    fn multiple(&self, magnitude: &Real) -> Self {
+        *self * *magnitude
+    }
+
+    fn dot(&self, other: &Self) -> Real {
+        (0..Self::DIM)
+            .map(|axis| self.axis(axis) * other.axis(axis))
+            .sum()
+    }
+
+    fn length_squared(&self) -> Real {
+        self.dot(self)
+    }
+
+    fn length(&self) -> Real {
+        self.length_squared().sqrt()
+    }
+
+    // return a vector that is normalized to the values contained in self. A
+    // zero length vector has no direction and comes back as NaN, see
+    // normalize_or_zero
+    fn normialize(&self) -> Self {
+        *self / self.length()
+    }
+
+    // same as normialize but a vector with no direction, zero or infinite
+    // length, comes back as zero
+    fn normalize_or_zero(&self) -> Self {
+        let length = self.length();
+        if length > 0.0 && length.is_finite() {
+            *self / length
+        } else {
+            Self::zero()
+        }
+    }
+
+    // the point fraction of the way from self to other. 0 is self and 1 is
+    // other
+    fn lerp(&self, other: &Self, fraction: &Real) -> Self {
+        *self + (*other - *self) * *fraction
+    }
+
+    fn get_distance(&self, other_position: &Self) -> Real {
+        (*other_position - *self).length()
+    }
+
+    // how far the position is outside of the square or cube of half_width
+    // around center along the axis where it is furthest out. Zero or less is
+    // inside
+    fn outside_by(&self, center: &Self, half_width: &Real) -> Real {
+        (0..Self::DIM)
+            .map(|axis| Real::abs(self.axis(axis) - center.axis(axis)) - half_width)
+            .fold(Real::MIN, Real::max)
+    }
+}
+
+impl Space for Vector {
+    const DIM: usize = 2;
+    const CHILDREN: usize = 4;
+    const MAX_DEPTH: u32 = 24;
+
+    fn zero() -> Vector {
+        Vector { x: 0.0, y: 0.0 }
+    }
+
+    fn axis(&self, axis: usize) -> Real {
+        match axis {
+            0 => self.x,
+            _ => self.y,
+        }
+    }
+
+    fn axis_mut(&mut self, axis: usize) -> &mut Real {
+        match axis {
+            0 => &mut self.x,
+            _ => &mut self.y,
+        }
+    }
+
+    // quadrants go anti clockwise from the upper right, see Tree::build_new_trees
+    fn child(&self, center: &Vector) -> usize {
+        if self.x >= center.x && self.y >= center.y {
+            0
+        } else if self.x < center.x && self.y >= center.y {
+            1
+        } else if self.x < center.x && self.y < center.y {
+            2
+        } else {
+            3
+        }
+    }
+
+    fn child_direction(child: usize) -> Vector {
+        [
+            Vector { x: 1.0, y: 1.0 },
+            Vector { x: -1.0, y: 1.0 },
+            Vector { x: -1.0, y: -1.0 },
+            Vector { x: 1.0, y: -1.0 },
+        ][child]
+    }
+
+    // Morton digits are ordered lower left, lower right, upper left then
+    // upper right
+    fn digit_to_child(digit: usize) -> usize {
+        [2, 3, 1, 0][digit]
+    }
+}
+
+impl Add for Vector {
+    type Output = Vector;
+
+    fn add(self, other: Vector) -> Vector {
+        Vector {
+            x: self.x + other.x,
+            y: self.y + other.y,
+        }
+    }
+}
+
+impl Sub for Vector {
+    type Output = Vector;
+
+    fn sub(self, other: Vector) -> Vector {
+        Vector {
+            x: self.x - other.x,
+            y: self.y - other.y,
+        }
+    }
+}
+
+impl Mul<Real> for Vector {
+    type Output = Vector;
+
+    fn mul(self, scale: Real) -> Vector {
+        Vector {
+            x: self.x * scale,
+            y: self.y * scale,
+        }
+    }
+}
+
+impl Div<Real> for Vector {
+    type Output = Vector;
+
+    fn div(self, scale: Real) -> Vector {
+        Vector {
+            x: self.x / scale,
+            y: self.y / scale,
+        }
+    }
+}
+
+impl Neg for Vector {
+    type Output = Vector;
+
+    fn neg(self) -> Vector {
+        Vector {
+            x: -self.x,
+            y: -self.y,
+        }
+    }
+}
+
+impl AddAssign for Vector {
+    fn add_assign(&mut self, other: Vector) {
+        self.x += other.x;
+        self.y += other.y;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Vector3 {
+    pub x: Real,
+    pub y: Real,
+    pub z: Real,
+}
+
+impl Vector3 {
+    // vector at right angles to both, following the right hand rule
+    pub fn cross(&self, other: &Vector3) -> Vector3 {
+        Vector3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+}
+
+impl Space for Vector3 {
+    const DIM: usize = 3;
+    const CHILDREN: usize = 8;
+    const MAX_DEPTH: u32 = 21;
+
+    fn zero() -> Vector3 {
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    fn axis(&self, axis: usize) -> Real {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z,
+        }
+    }
+
+    fn axis_mut(&mut self, axis: usize) -> &mut Real {
+        match axis {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            _ => &mut self.z,
+        }
+    }
+
+    // bit 0 of the octant is set on the positive side of x, bit 1 for y and
+    // bit 2 for z
+    fn child(&self, center: &Vector3) -> usize {
+        (self.x >= center.x) as usize
+            | ((self.y >= center.y) as usize) << 1
+            | ((self.z >= center.z) as usize) << 2
+    }
+
+    fn child_direction(child: usize) -> Vector3 {
+        let sign = |bit: usize| if child & bit != 0 { 1.0 } else { -1.0 };
+        Vector3 {
+            x: sign(0b001),
+            y: sign(0b010),
+            z: sign(0b100),
+        }
+    }
+
+    // octants use the same bit layout as the Morton digits
+    fn digit_to_child(digit: usize) -> usize {
+        digit
+    }
+}
+
+impl Add for Vector3 {
+    type Output = Vector3;
+
+    fn add(self, other: Vector3) -> Vector3 {
+        Vector3 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
+impl Sub for Vector3 {
+    type Output = Vector3;
+
+    fn sub(self, other: Vector3) -> Vector3 {
+        Vector3 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+}
+
+impl Mul<Real> for Vector3 {
+    type Output = Vector3;
+
+    fn mul(self, scale: Real) -> Vector3 {
+        Vector3 {
+            x: self.x * scale,
+            y: self.y * scale,
+            z: self.z * scale,
+        }
+    }
+}
+
+impl Div<Real> for Vector3 {
+    type Output = Vector3;
+
+    fn div(self, scale: Real) -> Vector3 {
+        Vector3 {
+            x: self.x / scale,
+            y: self.y / scale,
+            z: self.z / scale,
+        }
+    }
+}
+
+impl Neg for Vector3 {
+    type Output = Vector3;
+
+    fn neg(self) -> Vector3 {
+        Vector3 {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+}
+
+impl AddAssign for Vector3 {
+    fn add_assign(&mut self, other: Vector3) {
+        self.x += other.x;
+        self.y += other.y;
+        self.z += other.z;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every child must be on its own side of the center and child_direction
+    // must point into it
+    fn check_children<V: Space>(center: V) {
+        for child in 0..V::CHILDREN {
+            let inside = center + V::child_direction(child);
+            assert_eq!(inside.child(&center), child);
+        }
+        let mut digits: Vec<usize> = (0..V::CHILDREN).map(V::digit_to_child).collect();
+        digits.sort();
+        assert_eq!(digits, (0..V::CHILDREN).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn children_match_directions() {
+        check_children(Vector { x: 3.0, y: -2.0 });
+        check_children(Vector3 {
+            x: 3.0,
+            y: -2.0,
+            z: 7.0,
+        });
+    }
+
+    #[test]
+    fn vector3_distance() {
+        let a = Vector3 {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+        let b = Vector3 {
+            x: 3.0,
+            y: 5.0,
+            z: 9.0,
+        };
+        assert_eq!(a.get_distance(&b), 7.0);
+        assert_eq!(a.dot(&b), 3.0 + 10.0 + 27.0);
+        assert!(Real::abs((b - a).normialize().length() - 1.0) < 1e-6);
+    }
+
+    #[test]
+    fn vector3_cross() {
+        let x = Vector3 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let y = Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        assert_eq!(x.cross(&y), Vector3 { z: 1.0, ..y * 0.0 });
+        assert_eq!(y.cross(&x), -x.cross(&y));
+    }
+
+    #[test]
+    fn outside_by_uses_furthest_axis() {
+        let center = Vector3::zero();
+        let position = Vector3 {
+            x: 1.0,
+            y: -12.0,
+            z: 5.0,
+        };
+        assert_eq!(position.outside_by(&center, &10.0), 2.0);
+        assert!(position.outside_by(&center, &12.0) <= 0.0);
+    }
+}
@@ -0,0 +1,251 @@
+// Controls how much simulated time passes each step and each frame.
+use crate::float::Real;
+use crate::space::Space;
+use crate::tree::{Particle, G};
+
+// most steps taken in one frame when following real time. If the physics
+// can't keep up the extra time is dropped instead of piling up forever
+static MAX_STEPS_PER_FRAME: u32 = 10;
+
+// Decides how many steps to take each frame
+#[derive(Debug, Copy, Clone)]
+pub enum FrameSteps {
+    // one step per frame, so the speed of the simulation follows the frame rate
+    Fixed { taken: bool },
+    // follow the real time between frames. Time that doesn't fill a whole step
+    // is carried into the next frame
+    RealTime { accumulator: Real, taken: u32 },
+}
+
+impl FrameSteps {
+    pub fn fixed() -> FrameSteps {
+        FrameSteps::Fixed { taken: false }
+    }
+
+    pub fn real_time() -> FrameSteps {
+        FrameSteps::RealTime {
+            accumulator: 0.0,
+            taken: 0,
+        }
+    }
+
+    // call once at the start of every frame with how long the last frame took
+    pub fn start_frame(&mut self, frame_time: &Real) {
+        match self {
+            FrameSteps::Fixed { taken } => *taken = false,
+            FrameSteps::RealTime { accumulator, taken } => {
+                *accumulator += frame_time;
+                *taken = 0;
+            }
+        }
+    }
+
+    // whether another step of delta_time fits in this frame. The step size can
+    // change from one call to the next
+    pub fn next_step(&mut self, delta_time: &Real) -> bool {
+        match self {
+            FrameSteps::Fixed { taken } => !std::mem::replace(taken, true),
+            FrameSteps::RealTime { accumulator, taken } => {
+                if *taken >= MAX_STEPS_PER_FRAME {
+                    *accumulator = 0.0;
+                    return false;
+                }
+                if *accumulator < *delta_time {
+                    return false;
+                }
+                *accumulator -= delta_time;
+                *taken += 1;
+                true
+            }
+        }
+    }
+}
+
+// Picks the size of each step from how hard the particles are being pulled.
+// The step is eta * sqrt(softening / |a|) for the particle with the largest
+// acceleration so tight encounters get small steps and quiet systems get big
+// ones, kept between min_step and max_step
+#[derive(Debug, Copy, Clone)]
+pub struct AdaptiveStep {
+    // accuracy factor. Smaller values take smaller steps
+    pub eta: Real,
+    // length scale the step is measured against. Roughly the closest distance
+    // two particles are expected to get
+    pub softening: Real,
+    pub min_step: Real,
+    pub max_step: Real,
+}
+
+impl AdaptiveStep {
+    // settings sized to the particles so a scenario takes about as many steps
+    // per orbit whatever units it is in. The length is the distance from the
+    // center of mass to the furthest particle and the time is how long the
+    // system takes to fall together, sqrt(length^3 / (G * mass)). The
+    // softening is a twentieth of the length and the largest step a two
+    // hundredth of the time, which is what the N-body demos were tuned with.
+    // None if there is no mass or every particle is on the same spot
+    pub fn fit<V: Space>(list_of_points: &[Particle<V>]) -> Option<AdaptiveStep> {
+        let mass: Real = list_of_points.iter().map(|point| point.mass).sum();
+        if mass <= 0.0 {
+            return None;
+        }
+        let center_of_mass = list_of_points
+            .iter()
+            .fold(V::zero(), |sum, point| sum + point.position * point.mass)
+            / mass;
+        let length = list_of_points
+            .iter()
+            .map(|point| point.position.get_distance(&center_of_mass))
+            .fold(0.0, Real::max);
+        if length <= 0.0 {
+            return None;
+        }
+        let time = Real::sqrt(length * length * length / (G * mass));
+        let max_step = time / 200.0;
+        Some(AdaptiveStep {
+            eta: 0.2,
+            softening: length / 20.0,
+            min_step: max_step / 100.0,
+            max_step,
+        })
+    }
+
+    // step size for the accelerations held in g_vector. These are left behind by
+    // the force pass of the last step. Before the first step they are zero so
+    // the largest step is used
+    pub fn step_size<V: Space>(&self, list_of_points: &[Particle<V>]) -> Real {
+        let max_acceleration = list_of_points
+            .iter()
+            .map(|point| point.g_vector.length())
+            .fold(0.0, Real::max);
+        if max_acceleration == 0.0 {
+            return self.max_step;
+        }
+        let step = self.eta * Real::sqrt(self.softening / max_acceleration);
+        step.clamp(self.min_step, self.max_step)
+    }
+}
+
+// How big each step is
+#[derive(Debug, Copy, Clone)]
+pub enum StepSize {
+    Fixed(Real),
+    Adaptive(AdaptiveStep),
+}
+
+impl StepSize {
+    pub fn step_size<V: Space>(&self, list_of_points: &[Particle<V>]) -> Real {
+        match self {
+            StepSize::Fixed(delta_time) => *delta_time,
+            StepSize::Adaptive(adaptive) => adaptive.step_size(list_of_points),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::Vector;
+
+    // count the steps that fit in one frame
+    fn count_steps(frame_steps: &mut FrameSteps, frame_time: Real, delta_time: Real) -> u32 {
+        frame_steps.start_frame(&frame_time);
+        let mut steps = 0;
+        while frame_steps.next_step(&delta_time) {
+            steps += 1;
+        }
+        steps
+    }
+
+    #[test]
+    fn fixed_steps_once_per_frame() {
+        let mut frame_steps = FrameSteps::fixed();
+        assert_eq!(count_steps(&mut frame_steps, 0.5, 0.01), 1);
+        assert_eq!(count_steps(&mut frame_steps, 0.0, 0.01), 1);
+    }
+
+    #[test]
+    fn real_time_carries_left_over_time() {
+        let mut frame_steps = FrameSteps::real_time();
+        // 2.5 steps worth of time, half a step is carried over
+        assert_eq!(count_steps(&mut frame_steps, 0.625, 0.25), 2);
+        // which makes this frame 3 whole steps
+        assert_eq!(count_steps(&mut frame_steps, 0.625, 0.25), 3);
+        // a frame shorter than a step waits for the next one
+        assert_eq!(count_steps(&mut frame_steps, 0.125, 0.25), 0);
+        assert_eq!(count_steps(&mut frame_steps, 0.125, 0.25), 1);
+    }
+
+    #[test]
+    fn real_time_drops_time_it_cant_keep_up_with() {
+        let mut frame_steps = FrameSteps::real_time();
+        assert_eq!(
+            count_steps(&mut frame_steps, 100.0, 0.25),
+            MAX_STEPS_PER_FRAME
+        );
+        // the backlog isn't carried over
+        assert_eq!(count_steps(&mut frame_steps, 0.25, 0.25), 1);
+    }
+
+    #[test]
+    fn real_time_with_changing_steps() {
+        let mut frame_steps = FrameSteps::real_time();
+        frame_steps.start_frame(&1.0);
+        assert!(frame_steps.next_step(&0.5));
+        assert!(frame_steps.next_step(&0.25));
+        // only 0.25 is left
+        assert!(!frame_steps.next_step(&0.5));
+        assert!(frame_steps.next_step(&0.25));
+        assert!(!frame_steps.next_step(&0.125));
+    }
+
+    fn with_acceleration(x: Real, y: Real) -> Particle {
+        Particle {
+            position: Vector { x: 0.0, y: 0.0 },
+            velocity: Vector { x: 0.0, y: 0.0 },
+            g_vector: Vector { x, y },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 1.0,
+        }
+    }
+
+    #[test]
+    fn adaptive_step_follows_largest_acceleration() {
+        let adaptive = AdaptiveStep {
+            eta: 0.5,
+            softening: 4.0,
+            min_step: 0.001,
+            max_step: 1.0,
+        };
+        // nothing is pulling so take the largest step
+        assert_eq!(adaptive.step_size(&[with_acceleration(0.0, 0.0)]), 1.0);
+        // |a| = 100 so 0.5 * sqrt(4 / 100)
+        let list_of_points = [with_acceleration(1.0, 0.0), with_acceleration(60.0, 80.0)];
+        assert_eq!(adaptive.step_size(&list_of_points), 0.1);
+        // clamped at both ends
+        assert_eq!(adaptive.step_size(&[with_acceleration(1e9, 0.0)]), 0.001);
+        assert_eq!(adaptive.step_size(&[with_acceleration(1e-9, 0.0)]), 1.0);
+    }
+
+    #[test]
+    fn fit_scales_with_the_system() {
+        let mut list_of_points = [with_acceleration(0.0, 0.0), with_acceleration(0.0, 0.0)];
+        list_of_points[0].mass = 100.0;
+        list_of_points[1].position = Vector { x: 10.0, y: 0.0 };
+        let small = AdaptiveStep::fit(&list_of_points).unwrap();
+        // 100 times as far apart with 100^3 times the mass falls together in
+        // the same time
+        for point in &mut list_of_points {
+            point.position = point.position * 100.0;
+            point.mass *= 1e6;
+        }
+        let large = AdaptiveStep::fit(&list_of_points).unwrap();
+        assert!(Real::abs(large.softening / small.softening - 100.0) < 1e-3);
+        assert!(Real::abs(large.max_step / small.max_step - 1.0) < 1e-5);
+        assert_eq!(small.min_step, small.max_step / 100.0);
+        // nothing to measure
+        assert!(AdaptiveStep::fit(&list_of_points[..1]).is_none());
+        assert!(AdaptiveStep::fit::<Vector>(&[]).is_none());
+    }
+}
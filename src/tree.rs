@@ -1,316 +1,926 @@
-use std::mem;
-use std::{cell::RefCell, rc::Rc};
-static G: f32 = 1.0; //6.6743E-11; // distance in meters and mass in kg
-static BOX_SIZE: f32 = 1000.0;
-static THETA: f32 = 0.05;
+use std::{fmt, mem};
 
-#[derive(Debug, Copy, Clone)]
+#[cfg(feature = "window")]
+use macroquad::math::Vec2;
+
+#[cfg(feature = "window")]
+use crate::float::to_f32;
+use crate::float::Real;
+use crate::morton;
+use crate::softening::Softening;
+use crate::space::Space;
+// the simulation runs in units where G = 1, scenarios in real units are
+// converted by the units module
+pub(crate) static G: Real = 1.0;
+static BOX_SIZE: Real = 1000.0;
+
+// A position or direction in the plane. See the space module for Vector3 and
+// the vector math both share, the operators are implemented there as well
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Vector {
-    pub x: f32,
-    pub y: f32,
+    pub x: Real,
+    pub y: Real,
 }
 
 impl Vector {
-    // return a vector with the applied scale factor
-    pub fn multiple(&self, magnitude: &f32) -> Vector {
+    // z part of the cross product of the two vectors taken as 3D vectors in
+    // the plane. Positive when other is anti clockwise from self
+    pub fn cross(&self, other: &Vector) -> Real {
+        self.x * other.y - self.y * other.x
+    }
+
+    // turn the vector anti clockwise by angle radians
+    pub fn rotate(&self, angle: &Real) -> Vector {
+        let (sin, cos) = Real::sin_cos(*angle);
         Vector {
-            x: self.x * magnitude,
-            y: self.y * magnitude,
+            x: self.x * cos - self.y * sin,
+            y: self.x * sin + self.y * cos,
         }
     }
+}
 
-    // return a vector that is normalized to the values contained in self
-    pub fn normialize(&self) -> Vector {
-        let mag = f32::sqrt(f32::powi(self.x, 2) + f32::powi(self.y, 2));
+#[cfg(feature = "window")]
+impl From<Vec2> for Vector {
+    fn from(vector: Vec2) -> Vector {
         Vector {
-            x: self.x / mag,
-            y: self.y / mag,
+            x: vector.x as Real,
+            y: vector.y as Real,
         }
     }
-    pub fn get_distance(&self, other_position: &Vector) -> f32 {
-        // distance formula
-        let distance = ((f32::powi(other_position.y - self.y, 2))
-            + (f32::powi(other_position.x - self.x, 2)))
-        .sqrt();
-        distance
+}
+
+// macroquad works in f32 so this narrows when the physics runs in f64
+#[cfg(feature = "window")]
+impl From<Vector> for Vec2 {
+    fn from(vector: Vector) -> Vec2 {
+        Vec2::new(to_f32(&vector.x), to_f32(&vector.y))
     }
 }
+
+// V is the space the particle moves in, Vector for 2D or space::Vector3 for 3D
 #[derive(Debug, Copy, Clone)]
-pub struct Particle {
-    pub position: Vector,
-    pub velocity: Vector,
-    pub mass: f32,
-    pub g_vector: Vector,
+pub struct Particle<V = Vector> {
+    pub position: V,
+    pub velocity: V,
+    pub mass: Real,
+    pub g_vector: V,
+    // particles are solid disks, or balls in 3D, of this radius. Overlapping
+    // particles are found with Tree::collision_pairs and pushed apart by the
+    // collision module
+    pub radius: Real,
+    // softening length used by Softening::Adaptive
+    pub softening: Real,
 }
 
-impl Particle {
-    pub fn apply_force(&mut self, other: &Particle) {
-        // first calculate the force of gravity that will other particle applies on this
-        // particle
+impl<V: Space> Particle<V> {
+    // g_vector accumulates the acceleration of this particle, not the force, so
+    // it can be used directly by the integrators
+    pub fn apply_force(&mut self, other: &Particle<V>) {
+        self.apply_softened_force(other, &Softening::None);
+    }
+
+    // same as apply_force but the 1 / r^2 is replaced by the softening kernel
+    pub fn apply_softened_force(&mut self, other: &Particle<V>, softening: &Softening) {
+        // first calculate the acceleration of gravity that the other particle applies
+        // on this particle. F = G * m1 * m2 / r^2 and a = F / m1
         let distance = self.position.get_distance(&other.position);
-        let g_force: f32 = (G * self.mass * other.mass) / (f32::powi(distance, 2));
+        let g_force: Real =
+            G * other.mass * softening.acceleration(&distance, &self.softening, &other.softening);
         //create force vector
-        let g_vector = Vector {
-            x: other.position.x - self.position.x,
-            y: other.position.y - self.position.y,
-        }
-        .normialize()
-        .multiple(&g_force);
-
-        // second calculation to cancel out more and more the force of gravity as
-        // the object get closer
-        // if range greater than the collision range then it acts as a form of collision
-        // if the range is less than then the energy dissipation in the collision logic
-        // will mean the system balances
-        // if the range and the collision factor are the same then items stick
-        // This feels better than the collision system as it
-        //
-        // Currently keeping the collision logic contained in the main file but I think a p
-        // force like this is the way. This doesn't feel like the end solution for two reasons
-        // The collision logic removes energy from the system during collisions which is most
-        // likely what also needs to be done here. Also this P force is just the g force multiplied
-        // by how close it is getting. This might be wrong as I want the density to increase as
-        // particles have more force pushing on them.
-        static RANGE: f32 = 50.0;
-        let p_force: f32 = f32::max(0.0, RANGE - distance) * g_force;
-        let p_vector = Vector {
-            x: other.position.x - self.position.x,
-            y: other.position.y - self.position.y,
-        }
-        .normialize()
-        .multiple(&p_force);
-        self.g_vector.x = self.g_vector.x + g_vector.x - p_vector.x;
-        self.g_vector.y = self.g_vector.y + g_vector.y - p_vector.y;
-    }
-
-    pub fn update_position(&mut self, delta_time: &f32) {
-        self.position.x = self.position.x + self.velocity.x * delta_time;
-        self.position.y = self.position.y + self.velocity.y * delta_time;
-        self.g_vector.x = 0.0;
-        self.g_vector.y = 0.0;
-    }
-
-    pub fn update_velocity(&mut self, delta_time: &f32) {
-        self.velocity.x = self.velocity.x + self.g_vector.x * delta_time;
-        self.velocity.y = self.velocity.y + self.g_vector.y * delta_time;
+        let g_vector = (other.position - self.position)
+            .normalize_or_zero()
+            .multiple(&g_force);
+
+        self.g_vector += g_vector;
+    }
+
+    pub fn update_position(&mut self, delta_time: &Real) {
+        self.position += self.velocity * *delta_time;
+        self.g_vector = V::zero();
+    }
+
+    pub fn update_velocity(&mut self, delta_time: &Real) {
+        self.velocity += self.g_vector * *delta_time;
     }
 }
 
+// How the root of the tree is sized each time it is built
+#[derive(Debug, Copy, Clone)]
+pub enum Boundary {
+    // size the root to the bounding square of the particles so none are left out
+    Fit,
+    // keep a fixed square of the given half width around the origin. Particles
+    // outside of it are not added and append_node reports them back
+    Fixed(Real),
+}
+
+// returned when a particle falls outside of a fixed boundary
+#[derive(Debug, Copy, Clone)]
+pub struct OutOfBounds<V = Vector> {
+    pub position: V,
+}
+
+impl<V: Space> fmt::Display for OutOfBounds<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "position ")?;
+        for axis in 0..V::DIM {
+            if axis > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", self.position.axis(axis))?;
+        }
+        write!(f, " is out of bounds from the tree")
+    }
+}
+
+impl<V: Space> std::error::Error for OutOfBounds<V> {}
+
+// A single square of the quad tree, or cube of the octree. Nodes live in
+// Tree::nodes and refer to each other by index so the whole tree is one flat
+// allocation.
+#[derive(Debug, Copy, Clone)]
+struct Node<V> {
+    center: V,
+    // distance from the center to each edge of the square this node covers.
+    // Children are always built from this and never from the center position
+    half_width: Real,
+    // number of splits between this node and the root
+    depth: u32,
+    // index of the first of the children. The children are stored next to
+    // each other in the order set by Space::child so child n is at
+    // first_child + n
+    first_child: Option<usize>,
+    // head of the list of particles held by this leaf. A leaf holds a single
+    // particle unless it is at MAX_DEPTH where it becomes a bucket for
+    // everything that lands in it. See Tree::next_particle
+    first_particle: Option<usize>,
+    // total mass of every particle below this node
+    avg_mass: Real,
+    // mass weighted position of every particle below this node
+    center_of_mass: V,
+    // largest softening length of the particles below this node
+    softening: Real,
+    // traceless quadrupole moment about the center of mass,
+    // sum(m * (3 * d * d - |d|^2 * I)) with d measured from the center of
+    // mass. Only the upper triangle is kept, row by row, so xx, xy and yy in
+    // 2D and xx, xy, xz, yy, yz and zz in 3D
+    quadrupole: [Real; 6],
+}
+
+impl<V: Space> Node<V> {
+    fn new(center: V, half_width: Real, depth: u32) -> Node<V> {
+        Node {
+            center,
+            half_width,
+            depth,
+            first_child: None,
+            first_particle: None,
+            avg_mass: 0.0,
+            center_of_mass: V::zero(),
+            softening: 0.0,
+            quadrupole: [0.0; 6],
+        }
+    }
+
+    // index of the child that position falls in. See build_new_trees for the layout
+    fn child_of(&self, position: &V) -> usize {
+        position.child(&self.center)
+    }
+}
+
+// The tree is stored as an arena. nodes[0] is the root and every split
+// pushes all of its children on the end, so a child always has a larger
+// index than its parent. Calling reset empties the tree but keeps the
+// allocations so the same tree can be rebuilt every frame without going back
+// to the allocator. With the default Vector this is a quad tree, with
+// space::Vector3 it is an octree.
 #[derive(Debug)]
-pub struct Tree {
-    nodes: Vec<Rc<RefCell<Tree>>>,
-    center: Vector,
-    particle: Option<Particle>,
-    avg_mass: f32,
+pub struct Tree<V = Vector> {
+    nodes: Vec<Node<V>>,
+    // copies of every particle that has been appended
+    particles: Vec<Particle<V>>,
+    // next particle in the same leaf, parallel to particles
+    next_particle: Vec<Option<usize>>,
+    // index of each particle in the list it was appended from, parallel to
+    // particles. append_sorted stores the particles in a different order
+    source: Vec<usize>,
+    // number of particles offered to append_node since the last reset,
+    // including the ones that were out of bounds
+    offered: usize,
+    // kernel used for every interaction. Kept across resets
+    softening: Softening,
+    // add the quadrupole correction when a node is approximated. Kept across
+    // resets
+    quadrupole: bool,
+    // scratch space for append_sorted, kept so it doesn't allocate every frame
+    sort_keys: Vec<(u64, usize)>,
+}
+
+impl<V: Space> Default for Tree<V> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Tree {
+impl<V: Space> Tree<V> {
     // build and initialize the tree structure
     // The side length must be given as this will remain constant.
-    pub fn new() -> Tree {
-        Tree {
+    pub fn new() -> Tree<V> {
+        Tree::with_bounds(V::zero(), BOX_SIZE)
+    }
+
+    // build an empty tree whose root covers the square around center
+    pub fn with_bounds(center: V, half_width: Real) -> Tree<V> {
+        let mut tree = Tree {
             nodes: vec![],
-            center: Vector { x: 0.0, y: 0.0 },
-            particle: None,
-            avg_mass: 0.0,
-        }
+            particles: vec![],
+            next_particle: vec![],
+            source: vec![],
+            offered: 0,
+            softening: Softening::None,
+            quadrupole: true,
+            sort_keys: vec![],
+        };
+        tree.reset(center, half_width);
+        tree
     }
 
-    // Since building the quad tree is complex is will be the responsibility of
-    // the tree struct to build and manage it. While appending if a particle
-    // doesn't exist in the this tree object then add it. If it does exist
-    // then split this tree in four and append the node to the tree is fits in
-    pub fn append_node(&mut self, node: &Particle) {
-        if f32::abs(node.position.x) > BOX_SIZE || f32::abs(node.position.y) > BOX_SIZE {
-            // panic for to make dev easier
-            // println!(
-            //     "position {}, {} is out of bounds from the tree. It will not be added",
-            //     node.position.x, node.position.y
-            // );
-            return;
-        }
+    // build an empty tree with its root sized by the boundary policy. For
+    // Boundary::Fit the root is the bounding square of list_of_points so
+    // every particle in the list can be appended
+    pub fn with_boundary(boundary: &Boundary, list_of_points: &[Particle<V>]) -> Tree<V> {
+        let mut tree = Tree::new();
+        tree.reset_to_boundary(boundary, list_of_points);
+        tree
+    }
+
+    // pick the softening kernel used when the gravity is worked out
+    pub fn set_softening(&mut self, softening: Softening) {
+        self.softening = softening;
+    }
+
+    pub fn softening(&self) -> Softening {
+        self.softening
+    }
+
+    // Approximated nodes pull with their quadrupole moment as well as their
+    // mass by default. Turning it off leaves plain monopole Barnes-Hut which
+    // needs a smaller theta for the same accuracy
+    pub fn set_quadrupole(&mut self, quadrupole: bool) {
+        self.quadrupole = quadrupole;
+    }
 
-        if self.nodes.len() == 0 {
-            match &self.particle {
-                None => {
-                    self.particle = Some(*node);
+    // empty the tree and give it a new root. The memory used by the last
+    // build is kept around for the next one
+    pub fn reset(&mut self, center: V, half_width: Real) {
+        self.nodes.clear();
+        self.particles.clear();
+        self.next_particle.clear();
+        self.source.clear();
+        self.offered = 0;
+        self.nodes.push(Node::new(center, half_width, 0));
+    }
+
+    // same as reset but the root is sized by the boundary policy
+    pub fn reset_to_boundary(&mut self, boundary: &Boundary, list_of_points: &[Particle<V>]) {
+        match boundary {
+            Boundary::Fixed(half_width) => self.reset(V::zero(), *half_width),
+            Boundary::Fit => {
+                if list_of_points.is_empty() {
+                    self.reset(V::zero(), BOX_SIZE);
                     return;
                 }
-                Some(particle) => {
-                    if particle.position.x >= self.center.x && particle.position.y >= self.center.y
-                    {
-                        self.build_new_trees();
-                        let old_particle = mem::replace(&mut self.particle, None).unwrap();
-                        self.nodes[0].borrow_mut().append_node(&old_particle);
-                    } else if particle.position.x < self.center.x
-                        && particle.position.y >= self.center.y
-                    {
-                        self.build_new_trees();
-                        let old_particle = mem::replace(&mut self.particle, None).unwrap();
-                        self.nodes[1].borrow_mut().append_node(&old_particle);
-                    } else if particle.position.x < self.center.x
-                        && particle.position.y < self.center.y
-                    {
-                        self.build_new_trees();
-                        let old_particle = mem::replace(&mut self.particle, None).unwrap();
-                        self.nodes[2].borrow_mut().append_node(&old_particle);
-                    } else {
-                        self.build_new_trees();
-                        let old_particle = mem::replace(&mut self.particle, None).unwrap();
-                        self.nodes[3].borrow_mut().append_node(&old_particle);
+                let mut min = list_of_points[0].position;
+                let mut max = list_of_points[0].position;
+                for point in list_of_points {
+                    for axis in 0..V::DIM {
+                        let value = point.position.axis(axis);
+                        *min.axis_mut(axis) = Real::min(min.axis(axis), value);
+                        *max.axis_mut(axis) = Real::max(max.axis(axis), value);
                     }
                 }
+                let mut width: Real = 1.0;
+                let mut center = V::zero();
+                for axis in 0..V::DIM {
+                    width = Real::max(width, max.axis(axis) - min.axis(axis));
+                    *center.axis_mut(axis) = (min.axis(axis) + max.axis(axis)) / 2.0;
+                }
+                // pad the square a little so rounding in the center calculation
+                // can't push the outer most particles over the edge
+                self.reset(center, width * 0.505);
             }
         }
-        //figure out which quad to throw it in
-        if node.position.x >= self.center.x && node.position.y >= self.center.y {
-            self.nodes[0].borrow_mut().append_node(node);
-        } else if node.position.x < self.center.x && node.position.y >= self.center.y {
-            self.nodes[1].borrow_mut().append_node(node);
-        } else if node.position.x < self.center.x && node.position.y < self.center.y {
-            self.nodes[2].borrow_mut().append_node(node);
+    }
+
+    // Empty the tree and build it from every particle in the list, sized by
+    // the boundary policy. sorted_build picks append_sorted over calling
+    // append_node for each particle. Adaptive softening lengths are worked out
+    // and written back to list_of_points before the node summaries are built.
+    // Returns the number of particles left out because they are outside of a
+    // fixed boundary
+    pub fn rebuild(
+        &mut self,
+        boundary: &Boundary,
+        list_of_points: &mut [Particle<V>],
+        sorted_build: &bool,
+    ) -> usize {
+        self.reset_to_boundary(boundary, list_of_points);
+
+        // add to the tree. A copy will happen here which is required.
+        // The tree needs to be constant as the list of points vector
+        // is being updated
+        let mut out_of_bounds = 0;
+        if *sorted_build {
+            out_of_bounds = self.append_sorted(list_of_points).len();
         } else {
-            self.nodes[3].borrow_mut().append_node(node);
+            for point in &*list_of_points {
+                if self.append_node(point).is_err() {
+                    out_of_bounds += 1;
+                }
+            }
+        }
+        // adaptive softening lengths come from the nearest neighbours so they
+        // change every time the particles move
+        if let Softening::Adaptive { neighbours, scale } = self.softening {
+            self.adapt_softening(list_of_points, &neighbours, &scale);
         }
+        // calculate the average mass for each node
+        self.build_average_mass();
+        out_of_bounds
     }
 
-    // internal function to get an empty tree
-    fn new_tree(center: Vector) -> Rc<RefCell<Tree>> {
-        Rc::new(RefCell::new(Tree {
-            nodes: vec![],
-            center,
-            particle: None,
-            avg_mass: 0.0,
-        }))
+    // Since building the tree is complex is will be the responsibility of
+    // the tree struct to build and manage it. While appending if a particle
+    // doesn't exist in the this tree object then add it. If it does exist
+    // then split this tree into its children and append the node to the one
+    // it fits in. Particles outside of the root square are not added and are
+    // handed back as an error so the caller can decide what to do with them.
+    pub fn append_node(&mut self, node: &Particle<V>) -> Result<(), OutOfBounds<V>> {
+        let root = &self.nodes[0];
+        self.offered += 1;
+        if node.position.outside_by(&root.center, &root.half_width) > 0.0 {
+            return Err(OutOfBounds {
+                position: node.position,
+            });
+        }
+        self.particles.push(*node);
+        self.next_particle.push(None);
+        self.source.push(self.offered - 1);
+        self.insert_node(self.particles.len() - 1);
+        Ok(())
+    }
+
+    // Alternative to calling append_node for every particle. A Morton key is
+    // found for every particle, the keys are sorted and the tree is built by
+    // splitting the sorted list at each depth. The arena layout and the order
+    // the particles are stored in only depend on the positions, not on the
+    // order of list_of_points, and particles in the same leaf sit next to each
+    // other in memory. The tree must be empty, so call reset first. Particles
+    // outside of the root are left out and handed back.
+    pub fn append_sorted(&mut self, list_of_points: &[Particle<V>]) -> Vec<OutOfBounds<V>> {
+        debug_assert!(self.nodes.len() == 1 && self.particles.is_empty());
+        let root = self.nodes[0];
+        let mut out_of_bounds = vec![];
+        let mut keys = mem::take(&mut self.sort_keys);
+        keys.clear();
+        for (index, point) in list_of_points.iter().enumerate() {
+            if point.position.outside_by(&root.center, &root.half_width) > 0.0 {
+                out_of_bounds.push(OutOfBounds {
+                    position: point.position,
+                });
+                continue;
+            }
+            let key = morton::key(
+                &point.position,
+                &root.center,
+                &root.half_width,
+                &V::MAX_DEPTH,
+            );
+            keys.push((key, index));
+        }
+        keys.sort_unstable();
+
+        // store the particles in sorted order so the index of a particle in the
+        // tree matches its place in keys
+        for (_, index) in &keys {
+            self.particles.push(list_of_points[*index]);
+            self.next_particle.push(None);
+            self.source.push(*index);
+        }
+        self.build_sorted_range(0, &keys, 0);
+        self.sort_keys = keys;
+        out_of_bounds
+    }
+
+    // build the part of the tree under node from the sorted keys that fall in
+    // it. start is the index of the first of those particles
+    fn build_sorted_range(&mut self, node: usize, keys: &[(u64, usize)], start: usize) {
+        let depth = self.nodes[node].depth;
+        if keys.len() <= 1 || depth >= V::MAX_DEPTH {
+            // leaf, link the particles together in sorted order
+            let mut first_particle = None;
+            for index in (start..start + keys.len()).rev() {
+                self.next_particle[index] = first_particle;
+                first_particle = Some(index);
+            }
+            self.nodes[node].first_particle = first_particle;
+            return;
+        }
+        // the keys are sorted so the ones in each child are a run, in the
+        // order of their Morton digits
+        let first_child = self.build_new_trees(node);
+        let mut low = 0;
+        for digit in 0..V::CHILDREN {
+            let high = low
+                + keys[low..].partition_point(|(key, _)| {
+                    morton::digit::<V>(key, &depth, &V::MAX_DEPTH) <= digit
+                });
+            self.build_sorted_range(
+                first_child + V::digit_to_child(digit),
+                &keys[low..high],
+                start + low,
+            );
+            low = high;
+        }
+    }
+
+    // walk down from the root and place the particle at index. The bounds were
+    // already checked against the root
+    fn insert_node(&mut self, index: usize) {
+        let position = self.particles[index].position;
+        let mut current = 0;
+        loop {
+            let node = self.nodes[current];
+            if let Some(first_child) = node.first_child {
+                //figure out which child to throw it in
+                current = first_child + node.child_of(&position);
+                continue;
+            }
+            // an empty leaf takes the particle. Once the max depth is reached the
+            // leaf can't be split any further so it turns into a bucket that holds
+            // every particle that lands in it. This stops particles on the same
+            // spot from splitting forever
+            if node.first_particle.is_none() || node.depth >= V::MAX_DEPTH {
+                self.next_particle[index] = node.first_particle;
+                self.nodes[current].first_particle = Some(index);
+                return;
+            }
+            // otherwise split the leaf and move its particle down a layer. The
+            // loop then carries on into the new children
+            let first_child = self.build_new_trees(current);
+            let old_particle = self.nodes[current].first_particle.take().unwrap();
+            let quad = node.child_of(&self.particles[old_particle].position);
+            self.nodes[first_child + quad].first_particle = Some(old_particle);
+        }
     }
 
     // A quad is made up of four parts. Assuming that the center is origin then
     // right and up is positive and left and down is negative. The new quads
-    // will have the same offset just different signs. This offset can be
-    // calculated from the center position of the parent square as long
-    // as the first layer is pre-built
+    // will have the same offset just different signs. The offset is half of
+    // this node's half width which is also the half width of each child
     // ______+_____
     // |2    |1    |
     //-|_____|_____|+
     // |3    | 4   |
     // |_____|_____|
     //       -
-    fn build_new_trees(&mut self) {
-        // doesn't matter if x or y is chosen since it is a square.
-        // the absolute value is taken so the signs used to create the
-        // different quads remain the same.
-        let mut center_offset = f32::abs(self.center.x / 2.0);
-        if center_offset == 0.0 {
-            center_offset = BOX_SIZE / 2.0;
-        }
-        // quadrant 1
-        self.nodes.push(Tree::new_tree(Vector {
-            x: self.center.x + center_offset,
-            y: self.center.y + center_offset,
-        }));
-        // quadrant 2
-        self.nodes.push(Tree::new_tree(Vector {
-            x: self.center.x - center_offset,
-            y: self.center.y + center_offset,
-        }));
-        // quadrant 3
-        self.nodes.push(Tree::new_tree(Vector {
-            x: self.center.x - center_offset,
-            y: self.center.y - center_offset,
-        }));
-        // quadrant 4
-        self.nodes.push(Tree::new_tree(Vector {
-            x: self.center.x + center_offset,
-            y: self.center.y - center_offset,
-        }));
-    }
-
-    pub fn build_average_mass(&mut self) -> f32 {
-        match &self.particle {
-            // average mass is just the mass of the particle
-            Some(particle) => {
-                self.avg_mass = particle.mass;
-                self.avg_mass
+    // An octree node is split the same way into eight cubes, with bit 0 of
+    // the child index set on the positive x side, bit 1 for y and bit 2 for z.
+    // Space::child_direction gives the signs for each child.
+    // returns the index of the first child
+    fn build_new_trees(&mut self, parent: usize) -> usize {
+        let center = self.nodes[parent].center;
+        let center_offset = self.nodes[parent].half_width / 2.0;
+        let depth = self.nodes[parent].depth + 1;
+        let first_child = self.nodes.len();
+        for child in 0..V::CHILDREN {
+            self.nodes.push(Node::new(
+                center + V::child_direction(child) * center_offset,
+                center_offset,
+                depth,
+            ));
+        }
+        self.nodes[parent].first_child = Some(first_child);
+        first_child
+    }
+
+    // iterate over the particles held directly by a node
+    fn node_particles(&self, node: usize) -> impl Iterator<Item = &Particle<V>> + '_ {
+        let mut current = self.nodes[node].first_particle;
+        std::iter::from_fn(move || {
+            let index = current?;
+            current = self.next_particle[index];
+            Some(&self.particles[index])
+        })
+    }
+
+    // Walk the tree bottom up and store the total mass, the mass weighted
+    // center, the quadrupole moment and the largest softening length of every
+    // node. The far field approximation in get_acc_vector
+    // pulls from the center of mass, not the geometric center of the quad,
+    // so bodies clustered in one corner still pull from that corner.
+    // Children are always after their parent in the arena so walking it
+    // backwards visits every child before its parent. Returns the total mass.
+    pub fn build_average_mass(&mut self) -> Real {
+        for current in (0..self.nodes.len()).rev() {
+            let mut total_mass = 0.0;
+            let mut weighted = V::zero();
+            let mut softening: Real = 0.0;
+            // a leaf holds its particles directly
+            for particle in self.node_particles(current) {
+                total_mass += particle.mass;
+                weighted += particle.position * particle.mass;
+                softening = softening.max(particle.softening);
             }
-            None => {
-                if self.nodes.len() != 0 {
-                    self.avg_mass = self
-                        .nodes
-                        .iter()
-                        .fold(0.0, |sum, i| sum + i.borrow_mut().build_average_mass())
+            if let Some(first_child) = self.nodes[current].first_child {
+                for node in &self.nodes[first_child..first_child + V::CHILDREN] {
+                    total_mass += node.avg_mass;
+                    weighted += node.center_of_mass * node.avg_mass;
+                    softening = softening.max(node.softening);
                 }
-                self.avg_mass
             }
+            if total_mass == 0.0 {
+                let node = &mut self.nodes[current];
+                node.avg_mass = 0.0;
+                node.softening = softening;
+                continue;
+            }
+            let center_of_mass = weighted.multiple(&(1.0 / total_mass));
+
+            // the quadrupole needs the center of mass so it takes a second pass.
+            // Particles are point masses and each child adds its own moment
+            // moved over to this center of mass
+            let mut quadrupole = [0.0; 6];
+            for particle in self.node_particles(current) {
+                add_quadrupole(
+                    &mut quadrupole,
+                    &particle.position,
+                    &center_of_mass,
+                    &particle.mass,
+                );
+            }
+            if let Some(first_child) = self.nodes[current].first_child {
+                for node in &self.nodes[first_child..first_child + V::CHILDREN] {
+                    add_quadrupole(
+                        &mut quadrupole,
+                        &node.center_of_mass,
+                        &center_of_mass,
+                        &node.avg_mass,
+                    );
+                    for (total, part) in quadrupole.iter_mut().zip(node.quadrupole) {
+                        *total += part;
+                    }
+                }
+            }
+            let node = &mut self.nodes[current];
+            node.avg_mass = total_mass;
+            node.softening = softening;
+            node.center_of_mass = center_of_mass;
+            node.quadrupole = quadrupole;
         }
+        self.nodes[0].avg_mass
     }
 
     // public facing function to update all points in the tree
-    pub fn calc_gravity_vector(&self, list_of_points: &mut Vec<Particle>, delta_time: &f32) {
+    // theta is the opening angle used by the s/d < theta test. Smaller values
+    // open more nodes and are closer to the exact force but cost more time.
+    pub fn calc_gravity_vector(&self, list_of_points: &mut [Particle<V>], theta: &Real) {
         for point in list_of_points {
-            match &self.particle {
-                // early return since this is only one object in the tree
-                // we being safe out here
-                Some(_) => {
-                    return;
+            self.calc_point_gravity(point, theta);
+        }
+    }
+
+    // same as calc_gravity_vector but the particles are split across every
+    // core. The tree is only read and each particle still walks it in the same
+    // order, so the result is bit for bit the same as the serial version
+    #[cfg(feature = "parallel")]
+    pub fn calc_gravity_vector_parallel(&self, list_of_points: &mut [Particle<V>], theta: &Real) {
+        use rayon::prelude::*;
+        list_of_points
+            .par_iter_mut()
+            .for_each(|point| self.calc_point_gravity(point, theta));
+    }
+
+    // same as calc_gravity_vector but only the particles at the indices in
+    // active are updated. Used by block time steps where most particles are
+    // part way through a step and don't need a new acceleration
+    pub fn calc_gravity_vector_active(
+        &self,
+        list_of_points: &mut [Particle<V>],
+        active: &[usize],
+        theta: &Real,
+    ) {
+        for index in active {
+            self.calc_point_gravity(&mut list_of_points[*index], theta);
+        }
+    }
+
+    // Set the softening length of every particle for Softening::Adaptive. Each
+    // length is scale times the distance to the particle's nth nearest
+    // neighbour so particles in dense regions are softened less. Both the
+    // copies in the tree and list_of_points, which must be the list the tree
+    // was built from, are updated. Call this before build_average_mass so the
+    // nodes pick up the new lengths
+    pub fn adapt_softening(
+        &mut self,
+        list_of_points: &mut [Particle<V>],
+        neighbours: &usize,
+        scale: &Real,
+    ) {
+        debug_assert!(*neighbours > 0);
+        let mut nearest = vec![];
+        let mut stack = vec![];
+        for index in 0..self.particles.len() {
+            let position = self.particles[index].position;
+            // nearest holds the closest distances found so far in order. Nodes
+            // further away than the last of them can't get any closer
+            nearest.clear();
+            stack.push(0);
+            while let Some(current) = stack.pop() {
+                let node = &self.nodes[current];
+                if nearest.len() == *neighbours {
+                    let mut gap_squared = 0.0;
+                    for axis in 0..V::DIM {
+                        let gap = Real::max(
+                            Real::abs(position.axis(axis) - node.center.axis(axis))
+                                - node.half_width,
+                            0.0,
+                        );
+                        gap_squared += gap * gap;
+                    }
+                    if gap_squared >= nearest[neighbours - 1] * nearest[neighbours - 1] {
+                        continue;
+                    }
+                }
+                let mut other = node.first_particle;
+                while let Some(other_index) = other {
+                    other = self.next_particle[other_index];
+                    if other_index == index {
+                        continue;
+                    }
+                    let distance = position.get_distance(&self.particles[other_index].position);
+                    let place = nearest.partition_point(|near| *near <= distance);
+                    if place < *neighbours {
+                        nearest.insert(place, distance);
+                        nearest.truncate(*neighbours);
+                    }
+                }
+                if let Some(first_child) = node.first_child {
+                    stack.extend(first_child..first_child + V::CHILDREN);
+                }
+            }
+            // with fewer particles than neighbours use the furthest one found
+            let softening = nearest.last().map_or(0.0, |near| near * scale);
+            self.particles[index].softening = softening;
+            list_of_points[self.source[index]].softening = softening;
+        }
+    }
+
+    // Read only access to the arena for solvers that walk the tree themselves.
+    // Node indices follow the same rules as the arena, children always come
+    // after their parent
+    pub(crate) fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // center and half width of the square a node covers
+    pub(crate) fn node_square(&self, node: usize) -> (V, Real) {
+        (self.nodes[node].center, self.nodes[node].half_width)
+    }
+
+    pub(crate) fn node_mass(&self, node: usize) -> Real {
+        self.nodes[node].avg_mass
+    }
+
+    pub(crate) fn first_child(&self, node: usize) -> Option<usize> {
+        self.nodes[node].first_child
+    }
+
+    // tree indices of the particles held directly by a node
+    pub(crate) fn node_particle_indices(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut current = self.nodes[node].first_particle;
+        std::iter::from_fn(move || {
+            let index = current?;
+            current = self.next_particle[index];
+            Some(index)
+        })
+    }
+
+    // the tree's copy of a particle and the index it had in the list it was
+    // appended from
+    pub(crate) fn stored_particle(&self, index: usize) -> (&Particle<V>, usize) {
+        (&self.particles[index], self.source[index])
+    }
+
+    pub(crate) fn particle_count(&self) -> usize {
+        self.particles.len()
+    }
+
+    // whether the tree holds every particle of list_of_points where it is
+    // now, so it can be used instead of building a new one
+    pub fn holds(&self, list_of_points: &[Particle<V>]) -> bool {
+        self.particles.len() == list_of_points.len()
+            && self
+                .particles
+                .iter()
+                .zip(&self.source)
+                .all(|(point, source)| {
+                    list_of_points.get(*source).is_some_and(|other| {
+                        point.position == other.position && point.radius == other.radius
+                    })
+                })
+    }
+
+    // Broad phase for collisions. Fills pairs with every pair of particles
+    // that overlap, as indices into the list the tree was built from
+    // with the smaller index first. append_node indices count every particle
+    // offered to it, so build the tree from the whole list in order. For each
+    // particle only the nodes that come within reach of it are searched.
+    pub fn collision_pairs(&self, pairs: &mut Vec<(usize, usize)>) {
+        pairs.clear();
+        let max_radius = self
+            .particles
+            .iter()
+            .fold(0.0, |max, point| Real::max(max, point.radius));
+        let mut stack = vec![];
+        for (index, point) in self.particles.iter().enumerate() {
+            let reach = point.radius + max_radius;
+            stack.push(0);
+            while let Some(current) = stack.pop() {
+                let node = &self.nodes[current];
+                if point
+                    .position
+                    .outside_by(&node.center, &(node.half_width + reach))
+                    > 0.0
+                {
+                    continue;
                 }
-                None => {
-                    for node in &self.nodes {
-                        node.borrow_mut().get_acc_vector(point, delta_time)
+                let mut other = node.first_particle;
+                while let Some(other_index) = other {
+                    other = self.next_particle[other_index];
+                    // each pair is only found from the particle stored first
+                    if other_index <= index {
+                        continue;
                     }
+                    let other_point = &self.particles[other_index];
+                    if point.position.get_distance(&other_point.position)
+                        < point.radius + other_point.radius
+                    {
+                        let a = self.source[index];
+                        let b = self.source[other_index];
+                        pairs.push((usize::min(a, b), usize::max(a, b)));
+                    }
+                }
+                if let Some(first_child) = node.first_child {
+                    stack.extend(first_child..first_child + V::CHILDREN);
                 }
             }
         }
     }
 
+    // accumulate the gravity of the whole tree on one particle
+    fn calc_point_gravity(&self, point: &mut Particle<V>, theta: &Real) {
+        // a root without children is a single leaf so every particle
+        // in it is applied directly
+        self.apply_particles(0, point);
+        if let Some(first_child) = self.nodes[0].first_child {
+            for node in first_child..first_child + V::CHILDREN {
+                self.get_acc_vector(node, point, theta)
+            }
+        }
+    }
+
     // private recursive function to return get the acceleration vector to apply
     // to each node
-    fn get_acc_vector(&mut self, point: &mut Particle, delta_time: &f32) {
+    fn get_acc_vector(&self, current: usize, point: &mut Particle<V>, theta: &Real) {
+        let node = &self.nodes[current];
         // early return if the quad has zero mass. this means it is empty
-        if self.avg_mass == 0.0 {
+        if node.avg_mass == 0.0 {
             return;
         }
-        // check if the distance is far enough away to just use the average mass and center
-        // of the quad:  s/d > theta, s is the static width of the tree and distance is between
-        // the center and of the quad
-        if BOX_SIZE / self.center.get_distance(&point.position) < THETA {
-            if point.position.x != self.center.x && point.position.y != self.center.y {
-                point.apply_force(&Particle {
-                    mass: self.avg_mass,
-                    position: Vector {
-                        x: self.center.x,
-                        y: self.center.y,
+        // check if the distance is far enough away to just use the total mass and center
+        // of mass of the quad:  s/d < theta, s is the side length of this node and
+        // distance is between the point and the center of mass of the quad
+        let side_length = node.half_width * 2.0;
+        if side_length / node.center_of_mass.get_distance(&point.position) < *theta {
+            // don't apply the force if the point is sitting on the center of mass
+            if point.position != node.center_of_mass {
+                point.apply_softened_force(
+                    &Particle {
+                        mass: node.avg_mass,
+                        position: node.center_of_mass,
+                        velocity: V::zero(),
+                        g_vector: V::zero(),
+                        radius: 0.0,
+                        softening: node.softening,
                     },
-                    velocity: Vector { x: 0.0, y: 0.0 },
-                    g_vector: Vector { x: 0.0, y: 0.0 },
-                });
+                    &self.softening,
+                );
+                if self.quadrupole {
+                    point.g_vector += self.softened_quadrupole(node, point);
+                }
             }
             //early return as this the force has been applied
             return;
         }
-        match &self.particle {
-            // particle exists in this node so just apply that
-            Some(particle) => {
-                // don't apply the force if the point is in the same spot
-                if point.position.x != particle.position.x
-                    || point.position.y != particle.position.y
-                {
-                    point.apply_force(particle)
-                }
+        // particles exist in this node so just apply them
+        self.apply_particles(current, point);
+        if let Some(first_child) = node.first_child {
+            for node in first_child..first_child + V::CHILDREN {
+                self.get_acc_vector(node, point, theta)
             }
-            None => {
-                if self.nodes.len() != 0 {
-                    for node in &self.nodes {
-                        node.borrow_mut().get_acc_vector(point, delta_time)
-                    }
-                }
+        }
+    }
+
+    // the quadrupole correction of an approximated node, softened the same
+    // way as the pull of its mass. The expansion is for point masses so inside
+    // the softening length the correction is left out. Outside of it the
+    // correction is scaled by how much the kernel weakens the pull of the
+    // mass at that distance, which is exactly one for the spline
+    fn softened_quadrupole(&self, node: &Node<V>, point: &Particle<V>) -> V {
+        let distance = node.center_of_mass.get_distance(&point.position);
+        if distance < self.softening.length(&point.softening, &node.softening) {
+            return V::zero();
+        }
+        let weakened = self
+            .softening
+            .acceleration(&distance, &point.softening, &node.softening)
+            * distance
+            * distance;
+        quadrupole_acceleration(node, &point.position) * weakened
+    }
+
+    // apply the force from every particle held directly by a node
+    fn apply_particles(&self, node: usize, point: &mut Particle<V>) {
+        for particle in self.node_particles(node) {
+            // don't apply the force if the point is in the same spot
+            if point.position != particle.position {
+                point.apply_softened_force(particle, &self.softening)
+            }
+        }
+    }
+}
+
+// add the quadrupole moment of a point mass at position about center. See
+// Node::quadrupole for the layout
+fn add_quadrupole<V: Space>(quadrupole: &mut [Real; 6], position: &V, center: &V, mass: &Real) {
+    let d = *position - *center;
+    let distance_squared = d.dot(&d);
+    let mut index = 0;
+    for i in 0..V::DIM {
+        for j in i..V::DIM {
+            let mut part = 3.0 * d.axis(i) * d.axis(j);
+            if i == j {
+                part -= distance_squared;
+            }
+            quadrupole[index] += mass * part;
+            index += 1;
+        }
+    }
+}
+
+// Acceleration from the quadrupole moment of a node on a particle at
+// position, on top of the pull of its mass. With r from the center of mass
+// to the particle the potential term is -G * (r.Q.r) / (2 * r^5) and the
+// acceleration is G * (Q.r / r^5 - 5 * (r.Q.r) * r / (2 * r^7))
+fn quadrupole_acceleration<V: Space>(node: &Node<V>, position: &V) -> V {
+    let r = *position - node.center_of_mass;
+    let inverse_squared = 1.0 / r.dot(&r);
+    let inverse_fifth = inverse_squared * inverse_squared * Real::sqrt(inverse_squared);
+    // Q.r from the upper triangle, the parts off the diagonal count twice
+    let mut qr = V::zero();
+    let mut index = 0;
+    for i in 0..V::DIM {
+        for j in i..V::DIM {
+            let part = node.quadrupole[index];
+            *qr.axis_mut(i) += part * r.axis(j);
+            if i != j {
+                *qr.axis_mut(j) += part * r.axis(i);
             }
+            index += 1;
         }
     }
+    let radial = 2.5 * r.dot(&qr) * inverse_squared;
+    (qr - r * radial) * (G * inverse_fifth)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fixtures::{at_rest, ball, spiral};
+    use crate::float::consts;
+    use crate::solver::{DirectSum, ForceSolver};
+    use crate::space::Vector3;
+
+    // index of a child quad of a node
+    fn child(tree: &Tree, node: usize, quad: usize) -> usize {
+        tree.nodes[node].first_child.unwrap() + quad
+    }
+
+    // number of particles held directly by a node
+    fn leaf_len(tree: &Tree, node: usize) -> usize {
+        tree.node_particles(node).count()
+    }
 
     #[test]
     fn vector_multiple() {
@@ -328,9 +938,10 @@ mod tests {
         assert_eq!(vec1.x, 1.0);
         assert_eq!(vec1.y, 1.0);
 
+        // 1 / sqrt(2) rounded to whichever precision Real is
         let vec2 = vec1.normialize();
-        assert_eq!(vec2.x, 0.70710677);
-        assert_eq!(vec2.y, 0.70710677);
+        assert_eq!(vec2.x, 1.0 / Real::sqrt(2.0));
+        assert_eq!(vec2.y, 1.0 / Real::sqrt(2.0));
     }
 
     #[test]
@@ -339,7 +950,68 @@ mod tests {
         let vec2 = Vector { x: -1.0, y: -1.0 };
 
         let distance = vec1.get_distance(&vec2);
-        assert_eq!(distance, 2.828427);
+        assert_eq!(distance, Real::sqrt(8.0));
+    }
+
+    #[test]
+    fn vector_operators() {
+        let a = Vector { x: 1.0, y: 2.0 };
+        let b = Vector { x: 3.0, y: -4.0 };
+        assert_eq!(a + b, Vector { x: 4.0, y: -2.0 });
+        assert_eq!(a - b, Vector { x: -2.0, y: 6.0 });
+        assert_eq!(a * 2.0, Vector { x: 2.0, y: 4.0 });
+        assert_eq!(b / 2.0, Vector { x: 1.5, y: -2.0 });
+        assert_eq!(-a, Vector { x: -1.0, y: -2.0 });
+        let mut c = a;
+        c += b;
+        assert_eq!(c, a + b);
+    }
+
+    #[test]
+    fn vector_products_and_length() {
+        let a = Vector { x: 3.0, y: 4.0 };
+        let b = Vector { x: -4.0, y: 3.0 };
+        assert_eq!(a.length_squared(), 25.0);
+        assert_eq!(a.length(), 5.0);
+        assert_eq!(a.dot(&b), 0.0);
+        // b is a quarter turn anti clockwise from a
+        assert_eq!(a.cross(&b), 25.0);
+        assert_eq!(b.cross(&a), -25.0);
+    }
+
+    #[test]
+    fn vector_normalize_or_zero() {
+        assert_eq!(
+            Vector { x: 0.0, y: -2.0 }.normalize_or_zero(),
+            Vector { x: 0.0, y: -1.0 }
+        );
+        // no direction to keep so no NaN either
+        assert_eq!(Vector::zero().normalize_or_zero(), Vector::zero());
+        assert!(Vector::zero().normialize().x.is_nan());
+    }
+
+    #[test]
+    fn vector_rotate_and_lerp() {
+        let a = Vector { x: 2.0, y: 0.0 };
+        let turned = a.rotate(&(consts::PI / 2.0));
+        assert!(Real::abs(turned.x) < 1e-6);
+        assert!(Real::abs(turned.y - 2.0) < 1e-6);
+        // a full turn comes back around
+        assert!(a.rotate(&(2.0 * consts::PI)).get_distance(&a) < 1e-5);
+
+        let b = Vector { x: 4.0, y: -8.0 };
+        assert_eq!(a.lerp(&b, &0.0), a);
+        assert_eq!(a.lerp(&b, &1.0), b);
+        assert_eq!(a.lerp(&b, &0.25), Vector { x: 2.5, y: -2.0 });
+    }
+
+    #[cfg(feature = "window")]
+    #[test]
+    fn vector_to_and_from_vec2() {
+        let a = Vector { x: 1.5, y: -2.25 };
+        let screen = Vec2::from(a);
+        assert_eq!(screen, Vec2::new(1.5, -2.25));
+        assert_eq!(Vector::from(screen), a);
     }
 
     #[test]
@@ -348,12 +1020,16 @@ mod tests {
             position: Vector { x: 50.0, y: 50.0 },
             velocity: Vector { x: 1.0, y: 1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 50.0,
         };
         let part2 = Particle {
             position: Vector { x: -50.0, y: -50.0 },
             velocity: Vector { x: 1.0, y: 1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 50.0,
         };
 
@@ -368,12 +1044,31 @@ mod tests {
         assert_eq!(part2.velocity.y, 1.0);
     }
 
+    #[test]
+    fn particle_softened_force_on_the_same_spot() {
+        // a softened pull goes to zero at the center so two particles on the
+        // same spot leave each other alone instead of turning NaN
+        let part = Particle {
+            position: Vector { x: 3.0, y: -4.0 },
+            velocity: Vector::zero(),
+            g_vector: Vector::zero(),
+            radius: 0.0,
+            softening: 0.0,
+            mass: 50.0,
+        };
+        let mut point = part;
+        point.apply_softened_force(&part, &Softening::Plummer(1.0));
+        assert_eq!(point.g_vector, Vector::zero());
+    }
+
     #[test]
     fn particle_update_position() {
         let mut part = Particle {
             position: Vector { x: 1.0, y: 1.0 },
             velocity: Vector { x: -1.0, y: -1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 50.0,
         };
 
@@ -383,66 +1078,52 @@ mod tests {
         assert_eq!(part.position.y, 0.0);
     }
 
+    // the asserts on constants are how the original test checks which quads
+    // hold a particle
     #[test]
     fn tree_append_many_nodes() {
         let part1 = Particle {
             position: Vector { x: 1.0, y: 1.0 },
             velocity: Vector { x: 1.0, y: 1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 50.0,
         };
         let part2 = Particle {
             position: Vector { x: -1.0, y: -1.0 },
             velocity: Vector { x: 1.0, y: 1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 50.0,
         };
 
         let mut tree = Tree::new();
         //check for empty tree
-        assert_eq!(tree.avg_mass, 0.0);
-        match tree.particle {
-            None => assert!(true),
-            Some(_) => assert!(false),
-        }
-        assert_eq!(tree.nodes.len(), 0);
+        assert_eq!(tree.nodes[0].avg_mass, 0.0);
+        assert!(tree.node_particles(0).next().is_none());
+        assert!(tree.nodes[0].first_child.is_none());
 
-        tree.append_node(&part1);
+        tree.append_node(&part1).unwrap();
         //check for node in base position
-        assert_eq!(tree.avg_mass, 0.0);
-        match tree.particle {
-            None => assert!(false),
-            Some(_) => assert!(true),
-        }
-        assert_eq!(tree.nodes.len(), 0);
+        assert_eq!(tree.nodes[0].avg_mass, 0.0);
+        assert!(tree.node_particles(0).next().is_some());
+        assert!(tree.nodes[0].first_child.is_none());
 
-        tree.append_node(&part2);
+        tree.append_node(&part2).unwrap();
         //check for BOTH nodes to be moved into child nodes and out of base
         //check for node in base position
-        assert_eq!(tree.avg_mass, 0.0);
-        match tree.particle {
-            None => assert!(true),
-            Some(_) => assert!(false),
-        }
+        assert_eq!(tree.nodes[0].avg_mass, 0.0);
+        assert!(tree.node_particles(0).next().is_none());
 
         //items should only be in first and third quad
-        match tree.nodes[0].borrow_mut().particle {
-            None => assert!(false),
-            Some(_) => assert!(true),
-        }
-        match tree.nodes[1].borrow_mut().particle {
-            None => assert!(true),
-            Some(_) => assert!(false),
-        }
-        match tree.nodes[2].borrow_mut().particle {
-            None => assert!(false),
-            Some(_) => assert!(true),
-        }
-        match tree.nodes[3].borrow_mut().particle {
-            None => assert!(true),
-            Some(_) => assert!(false),
-        }
-        assert_eq!(tree.nodes.len(), 4);
+        assert!(tree.node_particles(child(&tree, 0, 0)).next().is_some());
+        assert!(tree.node_particles(child(&tree, 0, 1)).next().is_none());
+        assert!(tree.node_particles(child(&tree, 0, 2)).next().is_some());
+        assert!(tree.node_particles(child(&tree, 0, 3)).next().is_none());
+        // the root and its four children
+        assert_eq!(tree.nodes.len(), 5);
     }
 
     #[test]
@@ -451,32 +1132,36 @@ mod tests {
             position: Vector { x: 1.0, y: 1.0 },
             velocity: Vector { x: 1.0, y: 1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 50.0,
         };
         let part2 = Particle {
             position: Vector { x: -1.0, y: -1.0 },
             velocity: Vector { x: 1.0, y: 1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 100.0,
         };
 
         let mut tree = Tree::new();
-        tree.append_node(&part1);
-        tree.append_node(&part2);
+        tree.append_node(&part1).unwrap();
+        tree.append_node(&part2).unwrap();
 
         //should still be zero
-        assert_eq!(tree.avg_mass, 0.0);
+        assert_eq!(tree.nodes[0].avg_mass, 0.0);
 
         tree.build_average_mass();
 
-        assert_eq!(tree.avg_mass, 150.0);
+        assert_eq!(tree.nodes[0].avg_mass, 150.0);
 
         // first quad should have a mass of 50.0
-        assert_eq!(tree.nodes[0].borrow_mut().avg_mass, 50.0);
-        assert_eq!(tree.nodes[1].borrow_mut().avg_mass, 0.0);
+        assert_eq!(tree.nodes[child(&tree, 0, 0)].avg_mass, 50.0);
+        assert_eq!(tree.nodes[child(&tree, 0, 1)].avg_mass, 0.0);
         //third quad should have a mass of 100.0
-        assert_eq!(tree.nodes[2].borrow_mut().avg_mass, 100.0);
-        assert_eq!(tree.nodes[3].borrow_mut().avg_mass, 0.0);
+        assert_eq!(tree.nodes[child(&tree, 0, 2)].avg_mass, 100.0);
+        assert_eq!(tree.nodes[child(&tree, 0, 3)].avg_mass, 0.0);
     }
 
     #[test]
@@ -485,18 +1170,22 @@ mod tests {
             position: Vector { x: 1.0, y: 1.0 },
             velocity: Vector { x: 1.0, y: 1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 50.0,
         };
         let part2 = Particle {
             position: Vector { x: -1.0, y: -1.0 },
             velocity: Vector { x: 1.0, y: 1.0 },
             g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
             mass: 100.0,
         };
 
         let mut tree = Tree::new();
-        tree.append_node(&part1);
-        tree.append_node(&part2);
+        tree.append_node(&part1).unwrap();
+        tree.append_node(&part2).unwrap();
         tree.build_average_mass();
 
         // part1 and part2 are being copied here
@@ -513,7 +1202,7 @@ mod tests {
         assert_eq!(list_of_points[1].position.x, -1.0);
         assert_eq!(list_of_points[1].position.y, -1.0);
 
-        tree.calc_gravity_vector(&mut list_of_points, &100.0);
+        tree.calc_gravity_vector(&mut list_of_points, &0.5);
 
         for point in &mut list_of_points {
             point.update_velocity(&0.01);
@@ -528,4 +1217,910 @@ mod tests {
         assert_ne!(list_of_points[1].position.x, -1.0);
         assert_ne!(list_of_points[1].position.y, -1.0);
     }
+
+    #[test]
+    fn tree_build_center_of_mass() {
+        let part1 = Particle {
+            position: Vector { x: 1.0, y: 1.0 },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 50.0,
+        };
+        let part2 = Particle {
+            position: Vector { x: 3.0, y: 1.0 },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 150.0,
+        };
+        let part3 = Particle {
+            position: Vector { x: -2.0, y: -2.0 },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 200.0,
+        };
+
+        let mut tree = Tree::new();
+        tree.append_node(&part1).unwrap();
+        tree.append_node(&part2).unwrap();
+        tree.append_node(&part3).unwrap();
+        tree.build_average_mass();
+
+        assert_eq!(tree.nodes[0].avg_mass, 400.0);
+        // (50 * 1 + 150 * 3 - 200 * 2) / 400 and (50 * 1 + 150 * 1 - 200 * 2) / 400
+        assert_eq!(tree.nodes[0].center_of_mass, Vector { x: 0.25, y: -0.5 });
+
+        // both particles in the first quad pull from their weighted center
+        // and not from the middle of the quad
+        assert_eq!(tree.nodes[child(&tree, 0, 0)].avg_mass, 200.0);
+        assert_eq!(
+            tree.nodes[child(&tree, 0, 0)].center_of_mass,
+            Vector { x: 2.5, y: 1.0 }
+        );
+        assert_eq!(
+            tree.nodes[child(&tree, 0, 2)].center_of_mass,
+            Vector { x: -2.0, y: -2.0 }
+        );
+    }
+
+    #[test]
+    fn tree_node_width() {
+        let part1 = Particle {
+            position: Vector { x: 1.0, y: 1.0 },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 50.0,
+        };
+        let part2 = Particle {
+            position: Vector { x: 2.0, y: 2.0 },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 50.0,
+        };
+
+        let mut tree = Tree::new();
+        tree.append_node(&part1).unwrap();
+        tree.append_node(&part2).unwrap();
+
+        assert_eq!(tree.nodes[0].half_width, BOX_SIZE);
+        for quad in 0..4 {
+            assert_eq!(tree.nodes[child(&tree, 0, quad)].half_width, BOX_SIZE / 2.0);
+        }
+        // both particles share the first quad so it must be split again
+        let first = child(&tree, 0, 0);
+        for quad in 0..4 {
+            assert_eq!(
+                tree.nodes[child(&tree, first, quad)].half_width,
+                BOX_SIZE / 4.0
+            );
+        }
+    }
+
+    #[test]
+    fn tree_theta_opening() {
+        let part1 = Particle {
+            position: Vector { x: 100.0, y: 100.0 },
+            velocity: Vector { x: 0.0, y: 0.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 50.0,
+        };
+        let part2 = Particle {
+            position: Vector { x: 300.0, y: 100.0 },
+            velocity: Vector { x: 0.0, y: 0.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 150.0,
+        };
+        let probe = Particle {
+            position: Vector {
+                x: -900.0,
+                y: -900.0,
+            },
+            velocity: Vector { x: 0.0, y: 0.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 1.0,
+        };
+
+        // monopole only so the approximation is just the center of mass
+        let mut tree = Tree::new();
+        tree.set_quadrupole(false);
+        tree.append_node(&part1).unwrap();
+        tree.append_node(&part2).unwrap();
+        tree.build_average_mass();
+
+        // a theta of zero never approximates so the force is the exact sum
+        let mut exact = probe;
+        exact.apply_force(&part1);
+        exact.apply_force(&part2);
+        let mut list_of_points = vec![probe];
+        tree.calc_gravity_vector(&mut list_of_points, &0.0);
+        assert_eq!(list_of_points[0].g_vector, exact.g_vector);
+
+        // a large theta accepts the first quad and pulls from its center of mass
+        let mut approx = probe;
+        approx.apply_force(&Particle {
+            position: Vector { x: 250.0, y: 100.0 },
+            velocity: Vector { x: 0.0, y: 0.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 200.0,
+        });
+        let mut list_of_points = vec![probe];
+        tree.calc_gravity_vector(&mut list_of_points, &10.0);
+        assert_eq!(list_of_points[0].g_vector, approx.g_vector);
+        assert_ne!(list_of_points[0].g_vector, exact.g_vector);
+    }
+
+    #[test]
+    fn tree_fit_boundary() {
+        let part1 = Particle {
+            position: Vector { x: 1.0, y: 1.0 },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 50.0,
+        };
+        // well outside of the default box
+        let part2 = Particle {
+            position: Vector {
+                x: 5000.0,
+                y: -3000.0,
+            },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 100.0,
+        };
+        let list_of_points = vec![part1, part2];
+
+        let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        for point in &list_of_points {
+            assert!(tree.append_node(point).is_ok());
+        }
+        tree.build_average_mass();
+
+        // no particle was dropped
+        assert_eq!(tree.nodes[0].avg_mass, 150.0);
+    }
+
+    #[test]
+    fn tree_fixed_boundary_reports_out_of_bounds() {
+        let part1 = Particle {
+            position: Vector { x: 1.0, y: 1.0 },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 50.0,
+        };
+        let part2 = Particle {
+            position: Vector {
+                x: 5000.0,
+                y: -3000.0,
+            },
+            velocity: Vector { x: 1.0, y: 1.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 100.0,
+        };
+        let list_of_points = vec![part1, part2];
+
+        let mut tree = Tree::with_boundary(&Boundary::Fixed(1000.0), &list_of_points);
+        assert!(tree.append_node(&part1).is_ok());
+        match tree.append_node(&part2) {
+            Ok(_) => panic!("particle outside of the boundary was added"),
+            Err(out_of_bounds) => {
+                assert_eq!(
+                    out_of_bounds.position,
+                    Vector {
+                        x: 5000.0,
+                        y: -3000.0
+                    }
+                );
+            }
+        }
+        tree.build_average_mass();
+        assert_eq!(tree.nodes[0].avg_mass, 50.0);
+    }
+
+    // check that the four children of every split node exactly cover their parent.
+    // Each child must be half the size and sit in its own corner of the parent
+    fn assert_children_tile(tree: &Tree, parent: usize) {
+        let parent_node = tree.nodes[parent];
+        let first_child = match parent_node.first_child {
+            None => return,
+            Some(first_child) => first_child,
+        };
+        let half = parent_node.half_width / 2.0;
+        let expected = [
+            Vector { x: 1.0, y: 1.0 },
+            Vector { x: -1.0, y: 1.0 },
+            Vector { x: -1.0, y: -1.0 },
+            Vector { x: 1.0, y: -1.0 },
+        ];
+        for (quad, sign) in expected.iter().enumerate() {
+            let node = tree.nodes[first_child + quad];
+            assert_eq!(node.depth, parent_node.depth + 1);
+            assert_eq!(node.half_width, half);
+            assert_eq!(node.center, parent_node.center + *sign * half);
+            // the outer edge of the child lines up with the edge of the parent
+            assert_eq!(
+                node.center + *sign * node.half_width,
+                parent_node.center + *sign * parent_node.half_width
+            );
+            // and the inner edge lines up with the middle of the parent
+            assert_eq!(node.center - *sign * node.half_width, parent_node.center);
+            assert_children_tile(tree, first_child + quad);
+        }
+    }
+
+    #[test]
+    fn tree_children_tile_parent() {
+        // the old offset was taken from the center x position so any node where
+        // that didn't match its size, like (750, 250), got the wrong children
+        let positions = [
+            (1.0, 1.0),
+            (2.0, 1.0),
+            (260.0, 740.0),
+            (240.0, 760.0),
+            (740.0, 260.0),
+            (760.0, 240.0),
+            (-999.0, 999.0),
+            (-998.0, 998.0),
+            (0.0, 250.0),
+            (0.0, 251.0),
+        ];
+
+        let mut tree = Tree::new();
+        for (x, y) in positions {
+            tree.append_node(&Particle {
+                position: Vector { x, y },
+                velocity: Vector { x: 0.0, y: 0.0 },
+                g_vector: Vector { x: 0.0, y: 0.0 },
+                radius: 0.0,
+                softening: 0.0,
+                mass: 1.0,
+            })
+            .unwrap();
+        }
+        assert_children_tile(&tree, 0);
+    }
+
+    #[test]
+    fn tree_children_tile_off_center_root() {
+        // a root that is not centered on the origin has a center on the x axis
+        let mut tree = Tree::with_bounds(Vector { x: 0.0, y: 250.0 }, 250.0);
+        for (x, y) in [
+            (10.0, 260.0),
+            (20.0, 270.0),
+            (-100.0, 100.0),
+            (-90.0, 110.0),
+        ] {
+            tree.append_node(&Particle {
+                position: Vector { x, y },
+                velocity: Vector { x: 0.0, y: 0.0 },
+                g_vector: Vector { x: 0.0, y: 0.0 },
+                radius: 0.0,
+                softening: 0.0,
+                mass: 1.0,
+            })
+            .unwrap();
+        }
+        assert_children_tile(&tree, 0);
+    }
+
+    #[test]
+    fn tree_append_same_position() {
+        let part = Particle {
+            position: Vector { x: 10.0, y: 10.0 },
+            velocity: Vector { x: 0.0, y: 0.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 50.0,
+        };
+
+        let mut tree = Tree::new();
+        // would overflow the stack without a max depth
+        for _ in 0..3 {
+            tree.append_node(&part).unwrap();
+        }
+        tree.build_average_mass();
+        assert_eq!(tree.nodes[0].avg_mass, 150.0);
+        assert_eq!(tree.nodes[0].center_of_mass, Vector { x: 10.0, y: 10.0 });
+
+        // walk down to the bucket and check all three ended up in it
+        let mut node = 0;
+        while tree.nodes[node].first_child.is_some() {
+            node = child(&tree, node, tree.nodes[node].child_of(&part.position));
+        }
+        assert_eq!(tree.nodes[node].depth, Vector::MAX_DEPTH);
+        assert_eq!(leaf_len(&tree, node), 3);
+
+        // particles on the same spot don't pull on each other
+        let mut list_of_points = vec![part, part, part];
+        tree.calc_gravity_vector(&mut list_of_points, &0.5);
+        for point in &list_of_points {
+            assert_eq!(point.g_vector, Vector { x: 0.0, y: 0.0 });
+        }
+    }
+
+    #[test]
+    fn tree_append_dense_clump() {
+        let mut list_of_points = vec![];
+        for i in 0..200 {
+            list_of_points.push(Particle {
+                position: Vector {
+                    x: 100.0 + (i % 3) as Real * 1e-5,
+                    y: -100.0,
+                },
+                velocity: Vector { x: 0.0, y: 0.0 },
+                g_vector: Vector { x: 0.0, y: 0.0 },
+                radius: 0.0,
+                softening: 0.0,
+                mass: 1.0,
+            });
+        }
+        list_of_points.push(Particle {
+            position: Vector {
+                x: -100.0,
+                y: 100.0,
+            },
+            velocity: Vector { x: 0.0, y: 0.0 },
+            g_vector: Vector { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            softening: 0.0,
+            mass: 1.0,
+        });
+
+        let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        for point in &list_of_points {
+            tree.append_node(point).unwrap();
+        }
+        tree.build_average_mass();
+        assert_eq!(tree.nodes[0].avg_mass, 201.0);
+
+        tree.calc_gravity_vector(&mut list_of_points, &0.5);
+        for point in &list_of_points {
+            assert!(point.g_vector.length().is_finite());
+        }
+    }
+
+    #[test]
+    fn tree_reset_reuses_memory() {
+        let mut list_of_points = vec![];
+        for i in 0..100 {
+            list_of_points.push(Particle {
+                position: Vector {
+                    x: (i * 7 % 13) as Real * 10.0,
+                    y: (i * 3 % 17) as Real * -10.0,
+                },
+                velocity: Vector { x: 0.0, y: 0.0 },
+                g_vector: Vector { x: 0.0, y: 0.0 },
+                radius: 0.0,
+                softening: 0.0,
+                mass: 1.0,
+            });
+        }
+
+        let mut tree = Tree::new();
+        tree.reset_to_boundary(&Boundary::Fit, &list_of_points);
+        for point in &list_of_points {
+            tree.append_node(point).unwrap();
+        }
+        let node_count = tree.nodes.len();
+        let node_capacity = tree.nodes.capacity();
+        let particle_capacity = tree.particles.capacity();
+
+        // building the same frame again must not grow anything
+        tree.reset_to_boundary(&Boundary::Fit, &list_of_points);
+        assert_eq!(tree.nodes.len(), 1);
+        for point in &list_of_points {
+            tree.append_node(point).unwrap();
+        }
+        assert_eq!(tree.nodes.len(), node_count);
+        assert_eq!(tree.nodes.capacity(), node_capacity);
+        assert_eq!(tree.particles.capacity(), particle_capacity);
+        assert_eq!(tree.build_average_mass(), 100.0);
+    }
+
+    // points spread around a spiral
+    fn scattered_points() -> Vec<Particle> {
+        let mut list_of_points = spiral(
+            60,
+            |i| 13.0 + (i % 7) as Real * 41.3,
+            |i| 1.0 + (i % 5) as Real,
+        );
+        // a pair on the same spot to end up in a bucket
+        list_of_points.push(list_of_points[3]);
+        list_of_points
+    }
+
+    #[test]
+    fn tree_sorted_matches_insertion() {
+        let list_of_points = scattered_points();
+
+        let mut inserted = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        for point in &list_of_points {
+            inserted.append_node(point).unwrap();
+        }
+        inserted.build_average_mass();
+
+        let mut sorted = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        assert!(sorted.append_sorted(&list_of_points).is_empty());
+        sorted.build_average_mass();
+
+        assert_eq!(inserted.nodes.len(), sorted.nodes.len());
+        assert_eq!(inserted.nodes[0].avg_mass, sorted.nodes[0].avg_mass);
+
+        // both trees have the same shape so the forces must match exactly
+        let mut from_inserted = list_of_points.clone();
+        let mut from_sorted = list_of_points.clone();
+        inserted.calc_gravity_vector(&mut from_inserted, &0.5);
+        sorted.calc_gravity_vector(&mut from_sorted, &0.5);
+        for (a, b) in from_inserted.iter().zip(&from_sorted) {
+            assert_eq!(a.g_vector, b.g_vector);
+        }
+    }
+
+    #[test]
+    fn tree_sorted_matches_insertion_on_split_lines() {
+        // points on the lines the nodes split on and the next number over,
+        // where rounding the Morton key could pick the other side
+        let next = |value: Real| {
+            if value == 0.0 {
+                -Real::MIN_POSITIVE
+            } else {
+                Real::from_bits(value.to_bits() - 1)
+            }
+        };
+        let lines: Vec<Real> = [0.0, 25.0, -50.0, 75.0, 12.5, -6.25]
+            .iter()
+            .flat_map(|line| [*line, next(*line)])
+            .collect();
+        let mut list_of_points = vec![];
+        for x in &lines {
+            for y in &lines {
+                list_of_points.push(at_rest(Vector { x: *x, y: *y }, 1.0));
+            }
+        }
+
+        let boundary = Boundary::Fixed(100.0);
+        let mut inserted = Tree::with_boundary(&boundary, &list_of_points);
+        for point in &list_of_points {
+            inserted.append_node(point).unwrap();
+        }
+        let mut sorted = Tree::with_boundary(&boundary, &list_of_points);
+        assert!(sorted.append_sorted(&list_of_points).is_empty());
+
+        // the nodes are stored in a different order so walk both trees
+        // together. Every node must be split the same way and hold the same
+        // particles
+        fn assert_same(inserted: &Tree, a: usize, sorted: &Tree, b: usize) {
+            assert_eq!(inserted.nodes[a].center, sorted.nodes[b].center);
+            let mut held: Vec<Vector> = sorted.node_particles(b).map(|p| p.position).collect();
+            for point in inserted.node_particles(a) {
+                let found = held.iter().position(|position| *position == point.position);
+                held.swap_remove(found.unwrap());
+            }
+            assert!(held.is_empty());
+            match (inserted.nodes[a].first_child, sorted.nodes[b].first_child) {
+                (Some(a), Some(b)) => {
+                    for quad in 0..4 {
+                        assert_same(inserted, a + quad, sorted, b + quad);
+                    }
+                }
+                (a, b) => assert_eq!(a.is_some(), b.is_some()),
+            }
+        }
+        assert_eq!(inserted.nodes.len(), sorted.nodes.len());
+        assert_same(&inserted, 0, &sorted, 0);
+    }
+
+    #[test]
+    fn tree_sorted_is_deterministic() {
+        let list_of_points = scattered_points();
+        let mut reversed = list_of_points.clone();
+        reversed.reverse();
+
+        let mut tree1 = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        tree1.append_sorted(&list_of_points);
+        tree1.build_average_mass();
+        let mut tree2 = Tree::with_boundary(&Boundary::Fit, &reversed);
+        tree2.append_sorted(&reversed);
+        tree2.build_average_mass();
+
+        // the same layout no matter the order the particles came in
+        assert_eq!(tree1.nodes.len(), tree2.nodes.len());
+        for (a, b) in tree1.nodes.iter().zip(&tree2.nodes) {
+            assert_eq!(a.center, b.center);
+            assert_eq!(a.first_child, b.first_child);
+            assert_eq!(a.avg_mass, b.avg_mass);
+        }
+        for (a, b) in tree1.particles.iter().zip(&tree2.particles) {
+            assert_eq!(a.position, b.position);
+        }
+    }
+
+    #[test]
+    fn tree_sorted_reports_out_of_bounds() {
+        let list_of_points = scattered_points();
+        let mut tree = Tree::with_boundary(&Boundary::Fixed(100.0), &list_of_points);
+        let out_of_bounds = tree.append_sorted(&list_of_points);
+        let outside = list_of_points
+            .iter()
+            .filter(|point| point.position.outside_by(&Vector::zero(), &100.0) > 0.0)
+            .count();
+        assert!(outside > 0);
+        assert_eq!(out_of_bounds.len(), outside);
+        assert_eq!(tree.particles.len(), list_of_points.len() - outside);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn tree_parallel_matches_serial() {
+        let mut list_of_points = vec![];
+        for i in 0..2000 {
+            let angle = i as Real * 0.37;
+            let radius = 5.0 + (i % 97) as Real * 3.1;
+            list_of_points.push(Particle {
+                position: Vector {
+                    x: radius * Real::cos(angle),
+                    y: radius * Real::sin(angle),
+                },
+                velocity: Vector { x: 0.0, y: 0.0 },
+                g_vector: Vector { x: 0.0, y: 0.0 },
+                radius: 0.0,
+                softening: 0.0,
+                mass: 1.0 + (i % 3) as Real,
+            });
+        }
+        let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        tree.append_sorted(&list_of_points);
+        tree.build_average_mass();
+
+        let mut serial = list_of_points.clone();
+        let mut parallel = list_of_points.clone();
+        tree.calc_gravity_vector(&mut serial, &0.5);
+        tree.calc_gravity_vector_parallel(&mut parallel, &0.5);
+        for (a, b) in serial.iter().zip(&parallel) {
+            assert_eq!(a.g_vector.x.to_bits(), b.g_vector.x.to_bits());
+            assert_eq!(a.g_vector.y.to_bits(), b.g_vector.y.to_bits());
+        }
+    }
+
+    #[test]
+    fn tree_active_subset() {
+        let list_of_points = scattered_points();
+        let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        tree.append_sorted(&list_of_points);
+        tree.build_average_mass();
+
+        let mut all = list_of_points.clone();
+        tree.calc_gravity_vector(&mut all, &0.5);
+        let mut some = list_of_points.clone();
+        let active = [1, 7, 30];
+        tree.calc_gravity_vector_active(&mut some, &active, &0.5);
+
+        for (i, (a, b)) in all.iter().zip(&some).enumerate() {
+            if active.contains(&i) {
+                assert_eq!(a.g_vector, b.g_vector);
+            } else {
+                // particles that aren't active are left alone
+                assert_eq!(b.g_vector, Vector { x: 0.0, y: 0.0 });
+            }
+        }
+    }
+
+    #[test]
+    fn tree_collision_pairs_match_brute_force() {
+        let mut list_of_points = scattered_points();
+        for (i, point) in list_of_points.iter_mut().enumerate() {
+            point.radius = 5.0 + (i % 4) as Real * 10.0;
+        }
+        let mut expected = vec![];
+        for i in 0..list_of_points.len() {
+            for j in i + 1..list_of_points.len() {
+                let distance = list_of_points[i]
+                    .position
+                    .get_distance(&list_of_points[j].position);
+                if distance < list_of_points[i].radius + list_of_points[j].radius {
+                    expected.push((i, j));
+                }
+            }
+        }
+        assert!(expected.len() > 1);
+
+        // both ways of building the tree hand back list indices
+        let mut pairs = vec![];
+        let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        tree.append_sorted(&list_of_points);
+        tree.collision_pairs(&mut pairs);
+        pairs.sort();
+        assert_eq!(pairs, expected);
+
+        tree.reset_to_boundary(&Boundary::Fit, &list_of_points);
+        for point in &list_of_points {
+            tree.append_node(point).unwrap();
+        }
+        tree.collision_pairs(&mut pairs);
+        pairs.sort();
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn tree_softening_matches_direct() {
+        let list_of_points = scattered_points();
+        let softening = Softening::Plummer(20.0);
+        let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        tree.set_softening(softening);
+        tree.append_sorted(&list_of_points);
+        tree.build_average_mass();
+        // a theta of zero opens every node so the tree is a direct sum
+        let mut from_tree = list_of_points.clone();
+        tree.calc_gravity_vector(&mut from_tree, &0.0);
+
+        let mut direct = list_of_points.clone();
+        DirectSum::new(softening).calc_gravity(&mut direct);
+        for (direct_point, tree_point) in direct.iter().zip(&from_tree) {
+            assert!((direct_point.g_vector - tree_point.g_vector).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn tree_adapt_softening_matches_brute_force() {
+        let mut list_of_points = scattered_points();
+        let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        tree.append_sorted(&list_of_points);
+        tree.adapt_softening(&mut list_of_points, &3, &2.0);
+        tree.build_average_mass();
+
+        for (i, point) in list_of_points.iter().enumerate() {
+            let mut distances: Vec<Real> = list_of_points
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, other)| point.position.get_distance(&other.position))
+                .collect();
+            distances.sort_by(Real::total_cmp);
+            assert_eq!(point.softening, distances[2] * 2.0);
+        }
+        // the root holds the largest length
+        let largest = list_of_points
+            .iter()
+            .fold(0.0, |max, point| Real::max(max, point.softening));
+        assert_eq!(tree.nodes[0].softening, largest);
+    }
+
+    #[test]
+    fn tree_quadrupole_improves_accuracy() {
+        let list_of_points = scattered_points();
+        let mut exact = list_of_points.clone();
+        DirectSum::new(Softening::None).calc_gravity(&mut exact);
+        // median relative error of the tree at theta
+        let median_error = |quadrupole: bool, theta: Real| {
+            let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+            tree.set_quadrupole(quadrupole);
+            tree.append_sorted(&list_of_points);
+            tree.build_average_mass();
+            let mut approx = list_of_points.clone();
+            tree.calc_gravity_vector(&mut approx, &theta);
+            let mut errors: Vec<Real> = exact
+                .iter()
+                .zip(&approx)
+                .map(|(a, b)| (a.g_vector - b.g_vector).length() / a.g_vector.length())
+                .collect();
+            errors.sort_by(Real::total_cmp);
+            errors[errors.len() / 2]
+        };
+        for theta in [0.5, 0.8] {
+            let monopole = median_error(false, theta);
+            let quadrupole = median_error(true, theta);
+            assert!(
+                quadrupole * 2.0 < monopole,
+                "{} {} {}",
+                theta,
+                monopole,
+                quadrupole
+            );
+        }
+    }
+
+    #[test]
+    fn tree_quadrupole_of_a_pair() {
+        // two equal masses on the x axis either side of the center of mass
+        let mut tree = Tree::new();
+        for x in [-10.0, 10.0] {
+            tree.append_node(&Particle {
+                position: Vector { x, y: 0.0 },
+                velocity: Vector { x: 0.0, y: 0.0 },
+                g_vector: Vector { x: 0.0, y: 0.0 },
+                radius: 0.0,
+                softening: 0.0,
+                mass: 2.0,
+            })
+            .unwrap();
+        }
+        tree.build_average_mass();
+        // each adds m * (3 * 100 - 100) to xx and m * (0 - 100) to yy
+        assert_eq!(tree.nodes[0].quadrupole[..3], [800.0, 0.0, -400.0]);
+    }
+
+    #[test]
+    fn tree_quadrupole_is_softened() {
+        let list_of_points = scattered_points();
+        let tree_gravity = |softening: Softening, quadrupole: bool| {
+            let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+            tree.set_softening(softening);
+            tree.set_quadrupole(quadrupole);
+            tree.append_sorted(&list_of_points);
+            tree.build_average_mass();
+            let mut approx = list_of_points.clone();
+            tree.calc_gravity_vector(&mut approx, &0.8);
+            approx
+        };
+        // every node is inside a softening length longer than the whole
+        // system so no correction is added
+        let wide = Softening::Plummer(1.0e4);
+        for (with, without) in tree_gravity(wide, true)
+            .iter()
+            .zip(&tree_gravity(wide, false))
+        {
+            assert_eq!(with.g_vector, without.g_vector);
+        }
+        // with a short length the correction still brings the tree closer to
+        // the softened direct sum
+        let short = Softening::Plummer(5.0);
+        let mut exact = list_of_points.clone();
+        DirectSum::new(short).calc_gravity(&mut exact);
+        let median_error = |approx: Vec<Particle>| {
+            let mut errors: Vec<Real> = exact
+                .iter()
+                .zip(&approx)
+                .map(|(a, b)| (a.g_vector - b.g_vector).length() / a.g_vector.length())
+                .collect();
+            errors.sort_by(Real::total_cmp);
+            errors[errors.len() / 2]
+        };
+        let monopole = median_error(tree_gravity(short, false));
+        let quadrupole = median_error(tree_gravity(short, true));
+        assert!(quadrupole * 2.0 < monopole, "{} {}", monopole, quadrupole);
+    }
+
+    // points spread through a ball
+    fn scattered_points_3d() -> Vec<Particle<Vector3>> {
+        ball(
+            120,
+            |i| 17.0 + (i % 7) as Real * 37.9,
+            |i| 1.0 + (i % 5) as Real,
+        )
+    }
+
+    // the eight children of every split node are the octants of their parent,
+    // bit n of the child index set on the positive side of axis n
+    fn assert_octants(tree: &Tree<Vector3>, parent: usize) {
+        let parent_node = tree.nodes[parent];
+        let Some(first_child) = parent_node.first_child else {
+            return;
+        };
+        let half = parent_node.half_width / 2.0;
+        for octant in 0..8 {
+            let node = tree.nodes[first_child + octant];
+            assert_eq!(node.depth, parent_node.depth + 1);
+            assert_eq!(node.half_width, half);
+            for axis in 0..3 {
+                let sign = if octant & (1 << axis) != 0 { 1.0 } else { -1.0 };
+                assert_eq!(
+                    node.center.axis(axis),
+                    parent_node.center.axis(axis) + sign * half
+                );
+            }
+            assert_octants(tree, first_child + octant);
+        }
+    }
+
+    #[test]
+    fn octree_children_tile_parent() {
+        let list_of_points = scattered_points_3d();
+        let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        for point in &list_of_points {
+            tree.append_node(point).unwrap();
+        }
+        // every split pushes eight children
+        assert_eq!((tree.nodes.len() - 1) % 8, 0);
+        assert_octants(&tree, 0);
+        assert_eq!(tree.build_average_mass(), 360.0);
+    }
+
+    #[test]
+    fn octree_sorted_matches_insertion() {
+        let list_of_points = scattered_points_3d();
+
+        let mut inserted = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        for point in &list_of_points {
+            inserted.append_node(point).unwrap();
+        }
+        inserted.build_average_mass();
+
+        let mut sorted = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+        assert!(sorted.append_sorted(&list_of_points).is_empty());
+        sorted.build_average_mass();
+        assert_octants(&sorted, 0);
+        assert_eq!(inserted.nodes.len(), sorted.nodes.len());
+
+        let mut from_inserted = list_of_points.clone();
+        let mut from_sorted = list_of_points.clone();
+        inserted.calc_gravity_vector(&mut from_inserted, &0.5);
+        sorted.calc_gravity_vector(&mut from_sorted, &0.5);
+        for (a, b) in from_inserted.iter().zip(&from_sorted) {
+            assert_eq!(a.g_vector, b.g_vector);
+        }
+    }
+
+    #[test]
+    fn octree_quadrupole_improves_accuracy() {
+        let list_of_points = scattered_points_3d();
+        let mut exact = list_of_points.clone();
+        DirectSum::new(Softening::None).calc_gravity(&mut exact);
+        let median_error = |quadrupole: bool| {
+            let mut tree = Tree::with_boundary(&Boundary::Fit, &list_of_points);
+            tree.set_quadrupole(quadrupole);
+            tree.append_sorted(&list_of_points);
+            tree.build_average_mass();
+            let mut approx = list_of_points.clone();
+            tree.calc_gravity_vector(&mut approx, &0.6);
+            let mut errors: Vec<Real> = exact
+                .iter()
+                .zip(&approx)
+                .map(|(a, b)| a.g_vector.get_distance(&b.g_vector) / a.g_vector.length())
+                .collect();
+            errors.sort_by(Real::total_cmp);
+            errors[errors.len() / 2]
+        };
+        let monopole = median_error(false);
+        let quadrupole = median_error(true);
+        assert!(monopole < 0.05, "{}", monopole);
+        assert!(quadrupole * 2.0 < monopole, "{} {}", monopole, quadrupole);
+    }
+
+    #[test]
+    fn octree_quadrupole_of_a_pair() {
+        // two equal masses on the z axis either side of the center of mass
+        let mut tree = Tree::new();
+        for z in [-10.0, 10.0] {
+            tree.append_node(&Particle {
+                position: Vector3 { x: 0.0, y: 0.0, z },
+                velocity: Vector3::zero(),
+                g_vector: Vector3::zero(),
+                radius: 0.0,
+                softening: 0.0,
+                mass: 2.0,
+            })
+            .unwrap();
+        }
+        tree.build_average_mass();
+        // xx, xy, xz, yy, yz, zz
+        assert_eq!(
+            tree.nodes[0].quadrupole,
+            [-400.0, 0.0, 0.0, -400.0, 0.0, 800.0]
+        );
+    }
 }
@@ -0,0 +1,323 @@
+// Physical units.
+//
+// The simulation itself always runs with G = 1 (tree::G). A scenario can be
+// given in real units instead and converted on the way in. N-body systems
+// already have G = 1 and run as they are. SI and astronomical scenarios run
+// in the G = 1 system with the AU as its length and the solar mass as its
+// mass, so positions and masses of anything from planets to star clusters
+// stay near 1. Left in metres and kilograms a star's position times its
+// mass is past the largest f32.
+//
+// For example in astronomical units G is about 4 pi^2, so a year is about
+// 2 pi simulation time units and the Earth moves at about 1 AU per time unit.
+use crate::float::Real;
+use crate::space::Space;
+use crate::tree::Particle;
+
+// Newton's constant in m^3 / (kg s^2)
+pub static G_SI: Real = 6.6743e-11;
+// IAU astronomical unit in metres
+pub static ASTRONOMICAL_UNIT: Real = 1.495_978_7e11;
+// nominal solar mass in kilograms
+pub static SOLAR_MASS: Real = 1.98847e30;
+// Julian year in seconds
+pub static YEAR: Real = 3.15576e7;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UnitSystem {
+    // metres, kilograms and seconds
+    SI,
+    // astronomical units, solar masses and years
+    Astronomical,
+    // G = 1 with the given length in metres and mass in kilograms. The time
+    // unit follows from them. The demo scenarios use NBody(1.0, 1.0) which
+    // is the simulation units themselves
+    NBody { length: Real, mass: Real },
+}
+
+impl UnitSystem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnitSystem::SI => "SI",
+            UnitSystem::Astronomical => "AU, solar masses, years",
+            UnitSystem::NBody { .. } => "N-body",
+        }
+    }
+
+    // metres in one unit of length
+    pub fn length(&self) -> Real {
+        match self {
+            UnitSystem::SI => 1.0,
+            UnitSystem::Astronomical => ASTRONOMICAL_UNIT,
+            UnitSystem::NBody { length, .. } => *length,
+        }
+    }
+
+    // kilograms in one unit of mass
+    pub fn mass(&self) -> Real {
+        match self {
+            UnitSystem::SI => 1.0,
+            UnitSystem::Astronomical => SOLAR_MASS,
+            UnitSystem::NBody { mass, .. } => *mass,
+        }
+    }
+
+    // seconds in one unit of time
+    pub fn time(&self) -> Real {
+        match self {
+            UnitSystem::SI => 1.0,
+            UnitSystem::Astronomical => YEAR,
+            UnitSystem::NBody { length, mass } => {
+                // G_SI * mass * time^2 / length^3 = 1
+                Real::sqrt(length / (G_SI * mass)) * length
+            }
+        }
+    }
+
+    // G measured in this system
+    pub fn gravitational_constant(&self) -> Real {
+        match self {
+            UnitSystem::NBody { .. } => 1.0,
+            _ => {
+                let ratio = self.time() / self.length();
+                G_SI * self.mass() * ratio * ratio / self.length()
+            }
+        }
+    }
+
+    // the simulation's units of length, mass and time measured in this
+    // system's units
+    fn simulation_scale(&self) -> (Real, Real, Real) {
+        let (length, mass) = match self {
+            UnitSystem::NBody { .. } => (1.0, 1.0),
+            _ => (ASTRONOMICAL_UNIT / self.length(), SOLAR_MASS / self.mass()),
+        };
+        // G * mass * time^2 / length^3 = 1
+        let time = Real::sqrt(length / (self.gravitational_constant() * mass)) * length;
+        (length, mass, time)
+    }
+
+    // a time or duration in this system in simulation time units
+    pub fn to_simulation_time(&self, time: &Real) -> Real {
+        time / self.simulation_scale().2
+    }
+
+    // a simulation time or duration in this system's time units
+    pub fn from_simulation_time(&self, time: &Real) -> Real {
+        time * self.simulation_scale().2
+    }
+
+    // turn particles given in this system into simulation units in place
+    pub fn to_simulation<V: Space>(&self, list_of_points: &mut [Particle<V>]) {
+        let (length, mass, time) = self.simulation_scale();
+        for point in list_of_points {
+            point.position = point.position / length;
+            point.velocity = point.velocity * (time / length);
+            point.g_vector = point.g_vector * (time / length * time);
+            point.mass /= mass;
+            point.radius /= length;
+            point.softening /= length;
+        }
+    }
+
+    // turn particles in simulation units back into this system in place
+    pub fn from_simulation<V: Space>(&self, list_of_points: &mut [Particle<V>]) {
+        let (length, mass, time) = self.simulation_scale();
+        for point in list_of_points {
+            point.position = point.position * length;
+            point.velocity = point.velocity / (time / length);
+            point.g_vector = point.g_vector / (time / length * time);
+            point.mass *= mass;
+            point.radius *= length;
+            point.softening *= length;
+        }
+    }
+
+    // a length in this system measured in another
+    pub fn convert_length(&self, length: &Real, to: &UnitSystem) -> Real {
+        length * (self.length() / to.length())
+    }
+
+    // a mass in this system measured in another
+    pub fn convert_mass(&self, mass: &Real, to: &UnitSystem) -> Real {
+        mass * (self.mass() / to.mass())
+    }
+
+    // a time in this system measured in another
+    pub fn convert_time(&self, time: &Real, to: &UnitSystem) -> Real {
+        time * (self.time() / to.time())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::float::consts::PI;
+    use crate::integrator::{Integrator, Leapfrog};
+    use crate::simulation::Simulation;
+    use crate::softening::Softening;
+    use crate::solver::{DirectSum, ForceSolver};
+    use crate::tree::Vector;
+
+    fn relative(value: Real, expected: Real) -> Real {
+        Real::abs(value - expected) / Real::abs(expected)
+    }
+
+    #[test]
+    fn gravitational_constants() {
+        assert_eq!(UnitSystem::SI.gravitational_constant(), G_SI);
+        // Kepler's third law with a year and an AU gives 4 pi^2
+        let astronomical = UnitSystem::Astronomical.gravitational_constant();
+        assert!(
+            relative(astronomical, 4.0 * PI * PI) < 1e-3,
+            "{}",
+            astronomical
+        );
+        // an N-body time unit is the one that makes G come out as 1 in SI
+        let n_body = UnitSystem::NBody {
+            length: ASTRONOMICAL_UNIT,
+            mass: SOLAR_MASS,
+        };
+        let ratio = n_body.time() / n_body.length();
+        assert!(relative(G_SI * n_body.mass() * ratio * ratio / n_body.length(), 1.0) < 1e-5);
+        assert!(relative(n_body.time(), YEAR / (2.0 * PI)) < 1e-3);
+    }
+
+    #[test]
+    fn conversions_between_systems() {
+        let astronomical = UnitSystem::Astronomical;
+        assert_eq!(
+            astronomical.convert_length(&1.0, &UnitSystem::SI),
+            ASTRONOMICAL_UNIT
+        );
+        assert!(relative(UnitSystem::SI.convert_mass(&SOLAR_MASS, &astronomical), 1.0) < 1e-6);
+        assert!(relative(UnitSystem::SI.convert_time(&YEAR, &astronomical), 1.0) < 1e-6);
+        // simulation time goes there and back
+        let time = astronomical.to_simulation_time(&1.0);
+        assert!(relative(time, 2.0 * PI) < 1e-3);
+        assert!(relative(astronomical.from_simulation_time(&time), 1.0) < 1e-6);
+    }
+
+    #[test]
+    fn n_body_units_are_the_simulation() {
+        let units = UnitSystem::NBody {
+            length: 1.0,
+            mass: 1.0,
+        };
+        let point = Particle {
+            position: Vector { x: 3.0, y: 4.0 },
+            velocity: Vector { x: -1.5, y: 2.5 },
+            g_vector: Vector { x: 0.25, y: 0.5 },
+            radius: 1.0,
+            softening: 0.0,
+            mass: 7.0,
+        };
+        let mut list_of_points = [point];
+        units.to_simulation(&mut list_of_points);
+        assert_eq!(list_of_points[0].velocity, point.velocity);
+        assert_eq!(list_of_points[0].g_vector, point.g_vector);
+        assert_eq!(units.to_simulation_time(&2.0), 2.0);
+    }
+
+    #[test]
+    fn earth_orbits_in_a_year() {
+        // the Earth on a circular orbit of 1 AU at 2 pi AU per year
+        let sun = Particle {
+            position: Vector::zero(),
+            velocity: Vector::zero(),
+            g_vector: Vector::zero(),
+            radius: 0.0,
+            softening: 0.0,
+            mass: 1.0,
+        };
+        let earth = Particle {
+            position: Vector { x: 1.0, y: 0.0 },
+            velocity: Vector {
+                x: 0.0,
+                y: 2.0 * PI,
+            },
+            mass: 3.0e-6,
+            ..sun
+        };
+        let units = UnitSystem::Astronomical;
+        let mut list_of_points = vec![sun, earth];
+        units.to_simulation(&mut list_of_points);
+
+        let mut solver = DirectSum::new(Softening::None);
+        let mut direct = |list_of_points: &mut [Particle]| solver.calc_gravity(list_of_points);
+        let steps = 1000;
+        let delta_time = units.to_simulation_time(&1.0) / steps as Real;
+        let mut leapfrog = Leapfrog::default();
+        for _ in 0..steps {
+            leapfrog.step(&mut list_of_points, &delta_time, &mut direct);
+        }
+        // back where it started after a year, with the speed it started with
+        let distance = list_of_points[1].position.get_distance(&earth.position);
+        assert!(distance < 0.01, "{}", distance);
+        units.from_simulation(&mut list_of_points);
+        let speed = list_of_points[1].velocity.length();
+        assert!(relative(speed, 2.0 * PI) < 1e-3, "{}", speed);
+    }
+
+    // two suns on a circular orbit 6e11 m apart, in SI
+    fn binary_star() -> Vec<Particle> {
+        let sun = Particle {
+            position: Vector { x: 3.0e11, y: 0.0 },
+            velocity: Vector::zero(),
+            g_vector: Vector::zero(),
+            radius: 6.96e8,
+            softening: 0.0,
+            mass: SOLAR_MASS,
+        };
+        let speed = Real::sqrt(G_SI * SOLAR_MASS / (4.0 * 3.0e11));
+        vec![
+            Particle {
+                velocity: Vector { x: 0.0, y: speed },
+                ..sun
+            },
+            Particle {
+                position: -sun.position,
+                velocity: Vector { x: 0.0, y: -speed },
+                ..sun
+            },
+        ]
+    }
+
+    #[test]
+    fn si_goes_there_and_back() {
+        let units = UnitSystem::SI;
+        let mut list_of_points = binary_star();
+        units.to_simulation(&mut list_of_points);
+        // a sun weighs 1 and sits 2 AU out
+        assert!(relative(list_of_points[0].mass, 1.0) < 1e-6);
+        assert!(relative(list_of_points[0].position.x, 2.005) < 1e-3);
+        units.from_simulation(&mut list_of_points);
+        for (point, start) in list_of_points.iter().zip(&binary_star()) {
+            assert!(relative(point.position.x, start.position.x) < 1e-5);
+            assert!(relative(point.velocity.y, start.velocity.y) < 1e-5);
+            assert!(relative(point.mass, start.mass) < 1e-5);
+            assert!(relative(point.radius, start.radius) < 1e-5);
+        }
+        // simulation time measured in seconds
+        let time = units.to_simulation_time(&YEAR);
+        assert!(relative(time, 2.0 * PI) < 1e-3, "{}", time);
+    }
+
+    #[test]
+    fn si_scenario_steps() {
+        let units = UnitSystem::SI;
+        let mut list_of_points = binary_star();
+        units.to_simulation(&mut list_of_points);
+        let mut simulation = Simulation::new(list_of_points);
+        let start_energy = simulation.total_energy();
+        assert!(start_energy.is_finite(), "{}", start_energy);
+        for _ in 0..100 {
+            simulation.step();
+        }
+        for point in &simulation.particles {
+            assert!(point.position.x.is_finite() && point.position.y.is_finite());
+        }
+        let energy = simulation.total_energy();
+        assert!(relative(energy, start_energy) < 1e-3, "{}", energy);
+    }
+}